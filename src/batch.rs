@@ -0,0 +1,114 @@
+use std::io::BufRead;
+
+/// Server name argument meaning "read server names from stdin".
+pub const STDIN_MARKER: &str = "-";
+
+/// Tally of a batch run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub total: usize,
+    pub failed: usize,
+    /// Items never attempted because the batch was interrupted.
+    pub skipped: usize,
+}
+
+impl Summary {
+    pub fn succeeded(&self) -> usize {
+        self.total - self.failed - self.skipped
+    }
+
+    pub fn skip(&mut self, count: usize) {
+        self.total += count;
+        self.skipped += count;
+    }
+
+    pub fn record<T, E>(&mut self, result: &Result<T, E>) {
+        self.total += 1;
+        if result.is_err() {
+            self.failed += 1;
+        }
+    }
+}
+
+/// The items of a batch over `items` worth re-running: those at the indexes
+/// in `failed`, and those past the first `attempted`, which were never tried
+/// because the batch stopped early.
+pub fn leftovers<'a, T>(
+    items: &'a [T],
+    failed: &[usize],
+    attempted: usize,
+) -> (Vec<&'a T>, &'a [T]) {
+    let failed = failed.iter().filter_map(|&i| items.get(i)).collect();
+    (failed, items.get(attempted..).unwrap_or_default())
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} servers, {} succeeded, {} failed",
+            self.total,
+            self.succeeded(),
+            self.failed
+        )?;
+        if self.skipped > 0 {
+            write!(f, ", {} skipped", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read server names one per line, skipping blank lines and `#` comments.
+pub fn read_server_names<R: BufRead>(reader: R) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let name = line.trim();
+        if name.is_empty() || name.starts_with('#') {
+            continue;
+        }
+        names.push(name.to_string());
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leftovers, read_server_names, Summary};
+
+    #[test]
+    fn summary_counts() {
+        let mut summary = Summary::default();
+        summary.record::<(), ()>(&Ok(()));
+        summary.record::<(), ()>(&Err(()));
+        summary.record::<(), ()>(&Ok(()));
+        assert_eq!(summary.to_string(), "3 servers, 2 succeeded, 1 failed");
+
+        summary.skip(2);
+        assert_eq!(
+            summary.to_string(),
+            "5 servers, 2 succeeded, 1 failed, 2 skipped"
+        );
+    }
+
+    #[test]
+    fn leftovers_of_stopped_batch() {
+        let items = ["a", "b", "c", "d", "e"];
+        let (failed, skipped) = leftovers(&items, &[1], 3);
+        assert_eq!(failed, vec![&"b"]);
+        assert_eq!(skipped, ["d", "e"]);
+
+        let (failed, skipped) = leftovers(&items, &[0, 4], 5);
+        assert_eq!(failed, vec![&"a", &"e"]);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn skips_blanks_and_comments() {
+        let input = "be1/srv1\n\n  # a comment\n  be1/srv2  \n#be1/srv3\n";
+        let names = read_server_names(input.as_bytes()).unwrap();
+        assert_eq!(names, vec!["be1/srv1", "be1/srv2"]);
+    }
+}
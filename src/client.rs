@@ -1,106 +1,1562 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
-use serde_json;
-use tracing::debug;
+use tracing::{debug, info_span};
 
+use crate::http;
+pub use crate::http::{DEFAULT_CONNECT_TIMEOUT, DEFAULT_PING_TIMEOUT, DEFAULT_TIMEOUT};
 use crate::iam;
+use crate::routing;
+use crate::token::TokenProvider;
+
+type Result<T> = std::result::Result<T, ClientError>;
+
+/// Largest weight HAProxy accepts for a server, and the default upper bound
+/// `set_weight` validates against.
+pub const MAX_WEIGHT: u32 = 256;
+
+/// Path below the endpoint that API routes live under unless configured
+/// otherwise with `Client::with_api_base_path`.
+pub const DEFAULT_API_BASE_PATH: &str = "/v1";
+
+/// Separates the backend from the server in server names unless configured
+/// otherwise with `set_servername_delimiter`.
+pub const DEFAULT_SERVERNAME_DELIMITER: char = '/';
+
+static DELIMITER: OnceLock<char> = OnceLock::new();
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+/// Separate backend and server names with `delimiter` instead of `/`, for
+/// deployments named like `backend:server`. Must be called before any server
+/// name is parsed.
+pub fn set_servername_delimiter(delimiter: char) -> Result<()> {
+    DELIMITER.set(delimiter).map_err(|_| {
+        "server name delimiter already in use, it must be set first"
+            .to_string()
+            .into()
+    })
+}
 
-const DEFAULT_ENDPOINT: &str = "https://xenobuilds.mattbuilt.com";
-const DEFAULT_EU_ENDPOINT: &str = "https://hapctl-eu.kp-ops.net";
+/// The delimiter between backend and server in server names.
+pub fn servername_delimiter() -> char {
+    *DELIMITER.get_or_init(|| DEFAULT_SERVERNAME_DELIMITER)
+}
 
-#[derive(Debug, Clone)]
-struct InvalidServerNameError;
+/// Why a `Client` call failed, re-exported as `hapctl::Error`.
+///
+/// The variants below are stable and safe to match on. Failures without a
+/// variant of their own are `Other` for now and may get one later, so keep a
+/// wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// The server name is not `<backend>/<server>`.
+    InvalidServerName(String),
+    /// The weight is above the client's maximum.
+    WeightOutOfRange { weight: u32, max: u32 },
+    /// No token could be obtained from IAM.
+    Iam(iam::IamError),
+    /// The request could not be built, sent or its response read.
+    Http {
+        message: String,
+        source: reqwest::Error,
+    },
+    /// The response body is longer than the client's limit, see
+    /// `Client::with_max_response_size`.
+    ResponseTooLarge { limit: usize },
+    /// Anything else, such as a token provider or response parsing error.
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
 
-impl std::fmt::Display for InvalidServerNameError {
+impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "invalid server name given")
+        match self {
+            ClientError::InvalidServerName(name) => write!(
+                f,
+                "invalid server name '{}', expected <backend>{}<server>",
+                name,
+                servername_delimiter()
+            ),
+            ClientError::WeightOutOfRange { weight, max } => {
+                write!(f, "weight {} is out of range 0-{}", weight, max)
+            }
+            ClientError::Iam(e) => write!(f, "{}", e),
+            ClientError::Http { message, .. } => write!(f, "{}", message),
+            ClientError::ResponseTooLarge { limit } => {
+                write!(f, "response is larger than the {} byte limit", limit)
+            }
+            ClientError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Iam(e) => e.source(),
+            ClientError::Http { source, .. } => Some(source),
+            ClientError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<iam::IamError> for ClientError {
+    fn from(e: iam::IamError) -> Self {
+        ClientError::Iam(e)
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http {
+            message: e.to_string(),
+            source: e,
+        }
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Other(e.into())
+    }
+}
+
+impl From<String> for ClientError {
+    fn from(message: String) -> Self {
+        ClientError::Other(message.into())
+    }
+}
+
+/// Token providers return boxed errors; IAM failures among them keep their
+/// own variant. Other errors keep only their message, since they may not be
+/// `Send` or `Sync`.
+impl From<Box<dyn std::error::Error>> for ClientError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        match e.downcast::<iam::IamError>() {
+            Ok(e) => ClientError::Iam(*e),
+            Err(e) => ClientError::Other(e.to_string().into()),
+        }
+    }
+}
+
+/// A server named as `<backend>/<server>`, or with the delimiter set by
+/// `set_servername_delimiter`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ServerRef {
+    pub backend: String,
+    pub server: String,
+}
+
+impl std::str::FromStr for ServerRef {
+    type Err = ClientError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        ServerRef::parse_with(name, servername_delimiter())
+    }
+}
+
+impl ServerRef {
+    /// Parse `name`, which must contain `delimiter` exactly once.
+    pub fn parse_with(name: &str, delimiter: char) -> Result<Self> {
+        let parts: Vec<&str> = name.split(delimiter).collect();
+        if parts.len() != 2 {
+            return Err(ClientError::InvalidServerName(name.to_string()));
+        }
+
+        Ok(ServerRef {
+            backend: parts[0].to_string(),
+            server: parts[1].to_string(),
+        })
     }
 }
 
-impl std::error::Error for InvalidServerNameError {}
+impl std::fmt::Display for ServerRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.backend,
+            servername_delimiter(),
+            self.server
+        )
+    }
+}
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Body of a set-weight POST.
+///
+/// `reason` is normally free-form text. When metadata is attached (see
+/// [`structured_reason`]) it instead holds a JSON object serialized to a
+/// string, `{"reason":"<text>","meta":{"<key>":"<value>",...}}`, so servers
+/// that only know a string reason still accept it. Without a reason the field
+/// is left out, for servers that do not require one.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 struct SetWeightRequest {
     weight: u32,
-    reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct StructuredReason<'a> {
+    reason: &'a str,
+    meta: &'a BTreeMap<String, String>,
+}
+
+/// Combine a reason with key/value metadata (ticket IDs, change numbers...)
+/// into the structured reason format described on `SetWeightRequest`. With no
+/// metadata the reason is returned unchanged.
+pub fn structured_reason(reason: &str, meta: &BTreeMap<String, String>) -> String {
+    if meta.is_empty() {
+        return reason.to_string();
+    }
+
+    serde_json::to_string(&StructuredReason { reason, meta })
+        .expect("serializing string map cannot fail")
+}
+
+/// The weight in a `get_weight` response, either a JSON object with a
+/// `weight` field or a bare number. `None` if the body is neither.
+pub fn parse_weight(body: &str) -> Option<u32> {
+    let value: serde_json::Value = serde_json::from_str(body.trim()).ok()?;
+    let weight = value.get("weight").unwrap_or(&value);
+    std::convert::TryFrom::try_from(weight.as_u64()?).ok()
+}
+
+/// What `set_weight` applied, along with the server's own response, which
+/// varies between endpoints and may not echo the values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SetWeightResult {
+    pub server: ServerRef,
+    pub weight: u32,
+    pub reason: Option<String>,
+    pub server_response: String,
+}
+
+impl std::fmt::Display for SetWeightResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "set {} weight={}", self.server, self.weight)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " (reason: {})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Features the endpoint reports supporting, from `probe_endpoint`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Capabilities {
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// What every endpoint supports, assumed when it has no capabilities
+    /// route.
+    pub fn baseline() -> Self {
+        Capabilities {
+            version: None,
+            features: vec!["weight".to_string()],
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "version: {}\nfeatures: {}",
+            self.version.as_deref().unwrap_or("unknown"),
+            self.features.join(", ")
+        )
+    }
+}
+
+/// Outcome of a reachability check against the endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ping {
+    pub endpoint: String,
+    /// Status of `GET /healthz`.
+    pub status: reqwest::StatusCode,
+    /// Status of the authenticated `GET capabilities` request.
+    pub auth_status: reqwest::StatusCode,
+    pub auth_elapsed: Duration,
+    /// Time taken by both requests.
+    pub request_elapsed: Duration,
+}
+
+impl Ping {
+    /// The endpoint accepted our token on an authenticated route: it answered
+    /// with success, or 404 from an endpoint without the capabilities route.
+    /// Any other status, such as a 502 from a broken gateway, proves nothing.
+    pub fn auth_ok(&self) -> bool {
+        self.auth_status.is_success() || self.auth_status == reqwest::StatusCode::NOT_FOUND
+    }
+
+    /// Our token is accepted and `/healthz` reports success. An endpoint
+    /// without `/healthz` counts as healthy, as in `Client::health`.
+    pub fn healthy(&self) -> bool {
+        self.auth_ok()
+            && (self.status.is_success() || self.status == reqwest::StatusCode::NOT_FOUND)
+    }
+}
+
+impl std::fmt::Display for Ping {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: reachable, auth {} (HTTP {}), ",
+            self.endpoint,
+            match self.auth_status {
+                _ if self.auth_ok() => "ok",
+                reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => "rejected",
+                _ => "unknown",
+            },
+            self.auth_status.as_u16(),
+        )?;
+        if self.status == reqwest::StatusCode::NOT_FOUND {
+            write!(f, "no /healthz")?;
+        } else {
+            write!(f, "healthz HTTP {}", self.status.as_u16())?;
+        }
+        write!(
+            f,
+            ", token {}ms, request {}ms",
+            self.auth_elapsed.as_millis(),
+            self.request_elapsed.as_millis()
+        )
+    }
+}
+
+/// What the endpoint's health route reports, from `Client::health`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthStatus {
+    Known(Health),
+    /// The endpoint has no health route.
+    Unknown,
+}
+
+impl std::fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HealthStatus::Known(h) => write!(f, "{}", h),
+            HealthStatus::Unknown => write!(f, "no health route"),
+        }
+    }
+}
+
+/// A health report. Whatever the endpoint leaves out is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    pub http_status: reqwest::StatusCode,
+    /// The endpoint's own status, e.g. `ok` or `degraded`.
+    pub status: Option<String>,
+    pub version: Option<String>,
+    pub uptime: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HealthBody {
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default, alias = "uptime_secs", alias = "uptime_seconds")]
+    uptime: Option<f64>,
+}
+
+impl Health {
+    /// Parse a health response, which is either a JSON object with optional
+    /// `status`, `version` and `uptime` (seconds) fields or a plain text
+    /// status such as `ok`.
+    pub fn parse(http_status: reqwest::StatusCode, body: &str) -> Self {
+        let body = body.trim();
+        let parsed: HealthBody = match serde_json::from_str(body) {
+            Ok(b) => b,
+            Err(_) if body.is_empty() || body.starts_with('{') => HealthBody::default(),
+            Err(_) => HealthBody {
+                status: Some(body.to_string()),
+                ..HealthBody::default()
+            },
+        };
+
+        Health {
+            http_status,
+            status: parsed.status,
+            version: parsed.version,
+            uptime: parsed
+                .uptime
+                .filter(|s| s.is_finite() && *s >= 0.0)
+                .map(Duration::from_secs_f64),
+        }
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.http_status.is_success()
+    }
+}
+
+impl std::fmt::Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.http_status.as_u16())?;
+        if let Some(status) = &self.status {
+            write!(f, ", status {}", status)?;
+        }
+        if let Some(version) = &self.version {
+            write!(f, ", version {}", version)?;
+        }
+        if let Some(uptime) = self.uptime {
+            write!(f, ", up {}s", uptime.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// An HTTP response exactly as the server sent it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawResponse {
+    pub version: reqwest::Version,
+    pub status: reqwest::StatusCode,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: String,
+}
+
+impl std::fmt::Display for RawResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{:?} {}", self.version, self.status)?;
+        for (name, value) in &self.headers {
+            writeln!(f, "{}: {}", name, String::from_utf8_lossy(value.as_bytes()))?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.body)
+    }
 }
 
 pub struct Client {
     endpoint: String,
+    tokens: OnceLock<Arc<dyn TokenProvider>>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    ping_timeout: Duration,
+    idempotency_keys: bool,
+    log_body: bool,
+    auth_header: String,
+    auth_prefix: String,
+    debug_net: bool,
+    http: Option<reqwest::blocking::Client>,
+    weight_max: u32,
+    api_base_path: String,
+    max_response_size: usize,
+    default_reason: Option<String>,
+    correlation: Option<(String, String)>,
+    headers: reqwest::header::HeaderMap,
+    capabilities: OnceLock<Capabilities>,
 }
 
 impl Client {
     pub fn new(servername: &str, endpoint: Option<&str>) -> Self {
-        match endpoint {
-            Some(e) => Client { endpoint: e.into() },
-            None => {
-                if servername.contains("eu-de") {
-                    Client {
-                        endpoint: DEFAULT_EU_ENDPOINT.into(),
-                    }
-                } else {
-                    Client {
-                        endpoint: DEFAULT_ENDPOINT.into(),
-                    }
+        let endpoint = match endpoint {
+            Some(e) => e.into(),
+            None => routing::resolve(servername).endpoint,
+        };
+        Client::for_endpoint(&endpoint)
+    }
+
+    /// A client for `endpoint` that is not tied to any server, for commands
+    /// such as `ping` and `capabilities` that have no server to route by.
+    pub fn for_endpoint(endpoint: &str) -> Self {
+        Client {
+            endpoint: endpoint.to_string(),
+            tokens: OnceLock::new(),
+            connect_timeout: http::DEFAULT_CONNECT_TIMEOUT,
+            timeout: http::DEFAULT_TIMEOUT,
+            ping_timeout: http::DEFAULT_PING_TIMEOUT,
+            idempotency_keys: false,
+            log_body: false,
+            auth_header: "Authorization".to_string(),
+            auth_prefix: "Bearer ".to_string(),
+            debug_net: false,
+            http: None,
+            weight_max: MAX_WEIGHT,
+            api_base_path: DEFAULT_API_BASE_PATH.to_string(),
+            max_response_size: http::DEFAULT_MAX_RESPONSE_SIZE,
+            default_reason: None,
+            correlation: None,
+            headers: reqwest::header::HeaderMap::new(),
+            capabilities: OnceLock::new(),
+        }
+    }
+
+    /// The endpoint URL requests are sent to.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Send requests through `client` instead of the shared client (see
+    /// `http::set_shared_client`). Its own timeouts then take precedence over
+    /// the ones set on this client.
+    pub fn with_http_client(mut self, client: reqwest::blocking::Client) -> Self {
+        self.http = Some(client);
+        self
+    }
+
+    /// Limit how long establishing a connection to the endpoint may take.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Limit how long a whole request, including reading the body, may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Limit how long `ping` may take, independently of the operation
+    /// timeout.
+    pub fn with_ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Use the given IAM client for authentication instead of one built from the
+    /// environment. Clones of an `iam::Client` share a token cache, so passing a
+    /// clone to several clients fetches the token only once.
+    pub fn with_iam_client(mut self, iam: iam::Client) -> Self {
+        self.tokens = OnceLock::from(Arc::new(iam) as Arc<dyn TokenProvider>);
+        self
+    }
+
+    /// Get tokens from `provider` instead of IAM, e.g. a
+    /// `token::FileTokenProvider` reading a token maintained by another tool.
+    pub fn with_token_provider(mut self, provider: Arc<dyn TokenProvider>) -> Self {
+        self.tokens = OnceLock::from(provider);
+        self
+    }
+
+    /// The token provider every request of this client authenticates with,
+    /// built from the environment on first use if none was given. Pass it to
+    /// `with_token_provider` so other clients reuse its cached token.
+    pub fn token_provider(&self) -> Result<Arc<dyn TokenProvider>> {
+        Ok(self.tokens()?.clone())
+    }
+
+    /// Reject weights above `max` before sending them, for balancers
+    /// configured with a lower cap than HAProxy's 256. Raising it above what
+    /// the balancer accepts only moves the rejection to the server side.
+    pub fn with_weight_max(mut self, max: u32) -> Self {
+        self.weight_max = max;
+        self
+    }
+
+    /// Send API requests below `path` instead of `/v1`, for a newer API
+    /// version or an API mounted under a subpath, e.g. `/hapctl/v2`.
+    pub fn with_api_base_path(mut self, path: &str) -> Self {
+        self.api_base_path = path.to_string();
+        self
+    }
+
+    /// Send `reason` with weight changes made without one, i.e. `None` or an
+    /// empty string. A non-empty reason passed to the call always wins.
+    pub fn with_default_reason(mut self, reason: &str) -> Self {
+        self.default_reason = Some(reason.to_string());
+        self
+    }
+
+    /// The reason to send: `reason` unless it is missing or empty and there is
+    /// a default reason.
+    fn reason<'a>(&'a self, reason: Option<&'a str>) -> Option<&'a str> {
+        match (reason, &self.default_reason) {
+            (Some(r), _) if !r.is_empty() => Some(r),
+            (_, Some(default)) => Some(default),
+            (r, None) => r,
+        }
+    }
+
+    /// Fail requests whose response body is longer than `bytes`, rather than
+    /// buffering whatever a misbehaving endpoint sends.
+    pub fn with_max_response_size(mut self, bytes: usize) -> Self {
+        self.max_response_size = bytes;
+        self
+    }
+
+    /// Send an `Idempotency-Key` header with each `set_weight` call so a
+    /// retried POST is not applied twice. Off by default since not every
+    /// endpoint honors the header.
+    pub fn with_idempotency_keys(mut self, enabled: bool) -> Self {
+        self.idempotency_keys = enabled;
+        self
+    }
+
+    /// Include full response bodies in debug logs. Bodies may contain
+    /// sensitive data, so by default only the status and length are logged.
+    pub fn with_body_logging(mut self, enabled: bool) -> Self {
+        self.log_body = enabled;
+        self
+    }
+
+    /// Send the token in header `name` as `<prefix><token>`, for gateways that
+    /// expect something other than `Authorization: Bearer <token>`.
+    pub fn with_bearer_header_name(mut self, name: &str, prefix: &str) -> Self {
+        self.auth_header = name.to_string();
+        self.auth_prefix = prefix.to_string();
+        self
+    }
+
+    /// Send `id` in header `name` (e.g. `X-Correlation-ID`) with every request,
+    /// so a change can be traced through the endpoint's logs.
+    pub fn with_correlation_id(mut self, name: &str, id: &str) -> Self {
+        self.correlation = Some((name.to_string(), id.to_string()));
+        self
+    }
+
+    /// Send `name: value` with every request, e.g. a tenant ID a gateway
+    /// requires. It replaces any value the client sets itself, including
+    /// `Authorization` and `Content-Type`, so callers that do not mean to
+    /// override those should not pass them.
+    pub fn with_header(
+        mut self,
+        name: reqwest::header::HeaderName,
+        value: reqwest::header::HeaderValue,
+    ) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Headers kept out of transcripts: the auth header and every header
+    /// added with `with_header`, which often carry keys.
+    fn secret_headers(&self) -> Vec<&str> {
+        std::iter::once(self.auth_header.as_str())
+            .chain(self.headers.keys().map(|name| name.as_str()))
+            .collect()
+    }
+
+    /// Apply the `with_header` headers to a built request.
+    fn add_headers(&self, request: &mut reqwest::blocking::Request) {
+        for (name, value) in &self.headers {
+            request.headers_mut().insert(name, value.clone());
+        }
+        #[cfg(feature = "otel")]
+        crate::otel::inject(request.headers_mut());
+    }
+
+    /// Log a per-request timing breakdown (DNS, time to headers, total) at
+    /// info level, for this client and the IAM client it creates.
+    pub fn with_net_debug(mut self, enabled: bool) -> Self {
+        self.debug_net = enabled;
+        self
+    }
+
+    fn authorize(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+        token: &iam::Token,
+    ) -> reqwest::blocking::RequestBuilder {
+        let request = request.header(
+            self.auth_header.as_str(),
+            format!("{}{}", self.auth_prefix, token.access_token),
+        );
+
+        match &self.correlation {
+            Some((name, id)) => request.header(name.as_str(), id.as_str()),
+            None => request,
+        }
+    }
+
+    fn tokens(&self) -> Result<&Arc<dyn TokenProvider>> {
+        if let Some(t) = self.tokens.get() {
+            return Ok(t);
+        }
+
+        let iam = iam::Client::from_env()?
+            .with_connect_timeout(self.connect_timeout)
+            .with_timeout(self.timeout)
+            .with_net_debug(self.debug_net);
+        let iam = match &self.http {
+            Some(c) => iam.with_http_client(c.clone()),
+            None => iam,
+        };
+
+        Ok(self.tokens.get_or_init(|| Arc::new(iam)))
+    }
+
+    fn send(
+        &self,
+        c: &reqwest::blocking::Client,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<RawResponse> {
+        let mut request = request.build()?;
+        self.add_headers(&mut request);
+        let mut timer = self.debug_net.then(|| http::NetTimer::start(request.url()));
+        let mut transcript = http::Transcript::start(&request, &self.secret_headers());
+        let fail = |transcript: Option<http::Transcript>, e: reqwest::Error| {
+            let message = http::describe_error(&e, self.connect_timeout, self.timeout);
+            if let Some(t) = transcript {
+                t.failed(&message);
+            }
+            ClientError::Http { message, source: e }
+        };
+
+        let resp = match c.execute(request) {
+            Ok(r) => r,
+            Err(e) => return Err(fail(transcript, e)),
+        };
+        let version = resp.version();
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        if let Some(t) = timer.as_mut() {
+            t.headers_received();
+        }
+        if let Some(t) = transcript.as_mut() {
+            t.response(status, &headers);
+        }
+        let body = match http::read_body(resp, self.max_response_size) {
+            Ok(b) => b,
+            Err(http::BodyError::Transport(e)) => return Err(fail(transcript, e)),
+            Err(e) => {
+                let err = match e {
+                    http::BodyError::Io(e) => ClientError::Other(e.into()),
+                    _ => ClientError::ResponseTooLarge {
+                        limit: self.max_response_size,
+                    },
+                };
+                if let Some(t) = transcript {
+                    t.failed(&err.to_string());
                 }
+                return Err(err);
             }
+        };
+        if let Some(t) = timer {
+            t.finish(status);
+        }
+        if let Some(t) = transcript {
+            t.finish(Some(&body));
         }
+
+        if self.log_body {
+            debug!("status: {}, body: {:?}", status, body);
+        } else {
+            debug!("status: {}, body length: {}", status, body.len());
+        }
+        Ok(RawResponse {
+            version,
+            status,
+            headers,
+            body,
+        })
+    }
+
+    /// Check that the endpoint is reachable via `GET /healthz` and accepts
+    /// our token on the authenticated `GET capabilities` route, since
+    /// `/healthz` is usually open to anyone. The requests are limited by the
+    /// ping timeout (see `with_ping_timeout`) rather than the operation
+    /// timeout so an unhealthy endpoint fails fast.
+    pub fn ping(&self) -> Result<Ping> {
+        let _span = info_span!("request", operation = "ping").entered();
+
+        let start = Instant::now();
+        let token = self.tokens()?.token()?;
+        let auth_elapsed = start.elapsed();
+
+        let c = http::client_for(&self.http, self.connect_timeout, self.timeout)?;
+        let start = Instant::now();
+        let status = self.ping_request(&c, c.get(self.url("healthz")))?;
+        let auth_status = self.ping_request(
+            &c,
+            self.authorize(c.get(self.api_url(&["capabilities"])), &token),
+        )?;
+        let request_elapsed = start.elapsed();
+
+        Ok(Ping {
+            endpoint: self.endpoint.clone(),
+            status,
+            auth_status,
+            auth_elapsed,
+            request_elapsed,
+        })
+    }
+
+    /// Send one of `ping`'s requests, limited by the ping timeout, and return
+    /// its status.
+    fn ping_request(
+        &self,
+        c: &reqwest::blocking::Client,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<reqwest::StatusCode> {
+        let mut request = request.timeout(self.ping_timeout).build()?;
+        self.add_headers(&mut request);
+        let timer = self.debug_net.then(|| http::NetTimer::start(request.url()));
+        let transcript = http::Transcript::start(&request, &self.secret_headers());
+
+        let resp = match c.execute(request) {
+            Ok(r) => r,
+            Err(e) => {
+                let message = http::describe_error(
+                    &e,
+                    self.connect_timeout.min(self.ping_timeout),
+                    self.ping_timeout,
+                );
+                if let Some(t) = transcript {
+                    t.failed(&message);
+                }
+                return Err(ClientError::Http { message, source: e });
+            }
+        };
+        if let Some(t) = timer {
+            t.finish(resp.status());
+        }
+        if let Some(mut t) = transcript {
+            t.response(resp.status(), resp.headers());
+            t.finish(None);
+        }
+
+        debug!("status: {}", resp.status());
+        Ok(resp.status())
+    }
+
+    /// Ask the endpoint how it is doing, via `GET health` below the API base
+    /// path or, failing that, `GET /healthz`. Like `ping` the requests are
+    /// limited by the ping timeout. An endpoint with neither route gets
+    /// `HealthStatus::Unknown`.
+    pub fn health(&self) -> Result<HealthStatus> {
+        let _span = info_span!("request", operation = "health").entered();
+
+        let token = self.tokens()?.token()?;
+        let c = http::client_for(&self.http, self.connect_timeout, self.timeout)?;
+
+        for uri in [self.api_url(&["health"]), self.url("healthz")] {
+            let resp = self.send(
+                &c,
+                self.authorize(c.get(uri), &token)
+                    .timeout(self.ping_timeout),
+            )?;
+            if resp.status != reqwest::StatusCode::NOT_FOUND {
+                return Ok(HealthStatus::Known(Health::parse(resp.status, &resp.body)));
+            }
+        }
+
+        Ok(HealthStatus::Unknown)
+    }
+
+    /// Ask the endpoint which features it supports, via
+    /// `GET capabilities` below the API base path. Endpoints without that route get
+    /// `Capabilities::baseline()`. The answer is cached for the life of the
+    /// client.
+    pub fn probe_endpoint(&self) -> Result<Capabilities> {
+        if let Some(c) = self.capabilities.get() {
+            return Ok(c.clone());
+        }
+
+        let _span = info_span!("request", operation = "probe_endpoint").entered();
+
+        let token = self.tokens()?.token()?;
+        let c = http::client_for(&self.http, self.connect_timeout, self.timeout)?;
+        let resp = self.send(
+            &c,
+            self.authorize(c.get(self.api_url(&["capabilities"])), &token),
+        )?;
+
+        let capabilities = match resp.status {
+            reqwest::StatusCode::NOT_FOUND => Capabilities::baseline(),
+            s if s.is_success() => serde_json::from_str(&resp.body)
+                .map_err(|e| format!("invalid capabilities response: {}", e))?,
+            s => return Err(format!("probing capabilities failed: HTTP {}", s).into()),
+        };
+
+        Ok(self.capabilities.get_or_init(|| capabilities).clone())
     }
 
     pub fn get_weight(&self, server_name: &str) -> Result<String> {
-        let parts: Vec<&str> = server_name.split("/").collect();
-        if parts.len() != 2 {
-            return Err(InvalidServerNameError.into());
+        self.get_weight_ref(&server_name.parse()?)
+    }
+
+    /// Like `get_weight`, for a server name that is already parsed.
+    pub fn get_weight_ref(&self, server: &ServerRef) -> Result<String> {
+        Ok(self.get_weight_raw_ref(server)?.body)
+    }
+
+    /// Refuse to move `server_name` to `weight` if that changes its current
+    /// weight, read with `get_weight`, by more than `max_step`.
+    pub fn check_weight_step(&self, server_name: &str, weight: u32, max_step: u32) -> Result<()> {
+        let body = self.get_weight(server_name)?;
+        let current = parse_weight(&body).ok_or_else(|| {
+            format!(
+                "cannot read the current weight of {}: {}",
+                server_name,
+                body.trim()
+            )
+        })?;
+        if current.abs_diff(weight) > max_step {
+            return Err(format!(
+                "refusing to change {} from weight {} to {}: more than max_weight_step {} at once, ramp up gradually or pass --force",
+                server_name, current, weight, max_step
+            )
+            .into());
         }
+        Ok(())
+    }
 
-        let token = iam::Client::default().token()?;
+    /// Like `get_weight`, but return the status line and headers along with
+    /// the unparsed body.
+    pub fn get_weight_raw(&self, server_name: &str) -> Result<RawResponse> {
+        self.get_weight_raw_ref(&server_name.parse()?)
+    }
 
-        let uri = format!(
-            "{}/v1/backends/{}/servers/{}/weight",
-            self.endpoint, parts[0], parts[1]
-        );
+    /// Like `get_weight_raw`, for a server name that is already parsed.
+    pub fn get_weight_raw_ref(&self, server: &ServerRef) -> Result<RawResponse> {
+        let _span = info_span!(
+            "request",
+            operation = "get_weight",
+            backend = server.backend.as_str(),
+            server = server.server.as_str()
+        )
+        .entered();
 
-        let c = reqwest::blocking::Client::new();
-        let body = c
-            .get(uri)
-            .header("Authorization", format!("Bearer {}", token.access_token))
-            .send()?
-            .text()?;
+        let token = self.tokens()?.token()?;
 
-        debug!("body: {:?}", body);
-        Ok(body)
+        let uri = self.weight_url(server);
+
+        let c = http::client_for(&self.http, self.connect_timeout, self.timeout)?;
+        self.send(&c, self.authorize(c.get(uri), &token))
     }
 
-    pub fn set_weight(&self, server_name: &str, weight: u32, reason: &str) -> Result<String> {
-        let parts: Vec<&str> = server_name.split("/").collect();
-        if parts.len() != 2 {
-            return Err(InvalidServerNameError.into());
+    pub fn set_weight(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: &str,
+    ) -> Result<SetWeightResult> {
+        self.set_weight_opt(server_name, weight, Some(reason))
+    }
+
+    /// Like `set_weight`, but `None` leaves the reason out of the request
+    /// entirely instead of sending an empty one. Either way the default reason
+    /// (see `with_default_reason`) is sent instead if there is one.
+    pub fn set_weight_opt(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: Option<&str>,
+    ) -> Result<SetWeightResult> {
+        let reason = self.reason(reason);
+        let server = server_name.parse()?;
+        let resp = self.set_weight_raw_opt(server_name, weight, reason)?;
+
+        Ok(SetWeightResult {
+            server,
+            weight,
+            reason: reason.map(String::from),
+            server_response: resp.body,
+        })
+    }
+
+    /// Like `set_weight_opt`, but send `key` as the `Idempotency-Key` header
+    /// whether or not `with_idempotency_keys` is enabled, so repeating the
+    /// call with the same key is applied at most once.
+    pub fn set_weight_with_key(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: Option<&str>,
+        key: &str,
+    ) -> Result<SetWeightResult> {
+        let reason = self.reason(reason);
+        let server = server_name.parse()?;
+        let resp = self.post_weight(server_name, weight, reason, Some(key.to_string()))?;
+
+        Ok(SetWeightResult {
+            server,
+            weight,
+            reason: reason.map(String::from),
+            server_response: resp.body,
+        })
+    }
+
+    /// Send the write `repeat` times with one idempotency key, for servers
+    /// that sometimes drop writes, then read the weight back and fail unless
+    /// it took.
+    pub fn set_weight_repeated(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: Option<&str>,
+        repeat: u32,
+    ) -> Result<SetWeightResult> {
+        let key = http::new_uuid();
+        let mut result = self.set_weight_with_key(server_name, weight, reason, &key)?;
+        for _ in 1..repeat {
+            result = self.set_weight_with_key(server_name, weight, reason, &key)?;
         }
 
-        let token = iam::Client::default().token()?;
+        let body = self.get_weight(server_name)?;
+        match parse_weight(&body) {
+            Some(w) if w == weight => Ok(result),
+            Some(w) => Err(format!(
+                "{} has weight {} after {} writes of weight {}",
+                server_name, w, repeat, weight
+            )
+            .into()),
+            None => Err(format!(
+                "cannot verify the weight of {}: {}",
+                server_name,
+                body.trim()
+            )
+            .into()),
+        }
+    }
 
-        let uri = format!(
-            "{}/v1/backends/{}/servers/{}/weight",
-            self.endpoint, parts[0], parts[1]
-        );
+    /// Like `set_weight`, but return the HTTP status with the response body so
+    /// the caller can apply its own policy per status. Validation and
+    /// transport failures are still errors.
+    pub fn set_weight_status(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: &str,
+    ) -> Result<(reqwest::StatusCode, String)> {
+        let resp = self.set_weight_raw(server_name, weight, reason)?;
+        Ok((resp.status, resp.body))
+    }
+
+    /// Like `set_weight`, but return the status line and headers along with
+    /// the unparsed body.
+    pub fn set_weight_raw(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: &str,
+    ) -> Result<RawResponse> {
+        self.set_weight_raw_opt(server_name, weight, Some(reason))
+    }
+
+    /// Like `set_weight_raw`, with the reason optional as for
+    /// `set_weight_opt`.
+    pub fn set_weight_raw_opt(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: Option<&str>,
+    ) -> Result<RawResponse> {
+        let key = self.idempotency_keys.then(http::new_uuid);
+        self.post_weight(server_name, weight, reason, key)
+    }
+
+    fn post_weight(
+        &self,
+        server_name: &str,
+        weight: u32,
+        reason: Option<&str>,
+        idempotency_key: Option<String>,
+    ) -> Result<RawResponse> {
+        let reason = self.reason(reason);
+        let server: ServerRef = server_name.parse()?;
+        if weight > self.weight_max {
+            return Err(ClientError::WeightOutOfRange {
+                weight,
+                max: self.weight_max,
+            });
+        }
+
+        let _span = info_span!(
+            "request",
+            operation = "set_weight",
+            backend = server.backend.as_str(),
+            server = server.server.as_str()
+        )
+        .entered();
+
+        let token = self.tokens()?.token()?;
+
+        let uri = self.weight_url(&server);
         let reqdata = SetWeightRequest {
             weight,
-            reason: reason.to_string(),
+            reason: reason.map(String::from),
         };
 
         let request = serde_json::to_string(&reqdata)?;
 
-        let c = reqwest::blocking::Client::new();
-        let body = c
-            .post(uri)
-            .header("Authorization", format!("Bearer {}", token.access_token))
-            .header("Content-Type", "application/json")
-            .body(request)
-            .send()?
-            .text()?;
-
-        debug!("body: {:?}", body);
-        Ok(body.into())
+        let c = http::client_for(&self.http, self.connect_timeout, self.timeout)?;
+        let mut req = self
+            .authorize(c.post(uri), &token)
+            .header("Content-Type", "application/json");
+        if let Some(key) = idempotency_key {
+            debug!("idempotency key: {}", key);
+            req = req.header("Idempotency-Key", key);
+        }
+        self.send(&c, req.body(request))
+    }
+
+    /// Send an authenticated request to `path` on the endpoint, with `body`
+    /// as JSON if given, and return the status and unparsed body.
+    ///
+    /// This is a lower-level escape hatch for API calls that have no method
+    /// of their own yet. Nothing is validated, and a non-success status is
+    /// returned rather than treated as an error.
+    pub fn raw_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&str>,
+    ) -> Result<(reqwest::StatusCode, String)> {
+        let _span = info_span!("request", operation = "raw", method = %method, path).entered();
+
+        let token = self.tokens()?.token()?;
+
+        let c = http::client_for(&self.http, self.connect_timeout, self.timeout)?;
+        let mut req = self.authorize(c.request(method, self.url(path)), &token);
+        if let Some(body) = body {
+            req = req
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+        }
+
+        let resp = self.send(&c, req)?;
+        Ok((resp.status, resp.body))
+    }
+
+    /// The URL of `path`, which may have a query, below the endpoint's own
+    /// path. The `url` crate does the joining so IPv6 hosts, ports and base
+    /// paths survive intact.
+    fn url(&self, path: &str) -> String {
+        let (path, query) = match path.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (path, None),
+        };
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        match self.url_for(&segments) {
+            Some(mut url) => {
+                url.set_query(query);
+                url.to_string()
+            }
+            // Not a URL: let reqwest report it when the request is built.
+            None => format!("{}/{}", self.endpoint, path),
+        }
+    }
+
+    fn weight_url(&self, server: &ServerRef) -> String {
+        self.api_url(&[
+            "backends",
+            &server.backend,
+            "servers",
+            &server.server,
+            "weight",
+        ])
+    }
+
+    /// The URL of the API route made of `segments`, below the API base path.
+    fn api_url(&self, segments: &[&str]) -> String {
+        let segments: Vec<&str> = self
+            .api_base_path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .chain(segments.iter().copied())
+            .collect();
+
+        match self.url_for(&segments) {
+            Some(url) => url.to_string(),
+            None => format!("{}/{}", self.endpoint, segments.join("/")),
+        }
+    }
+
+    /// The endpoint with `segments` appended to its path, each percent-encoded
+    /// as needed. `None` if the endpoint is not a base URL.
+    fn url_for(&self, segments: &[&str]) -> Option<url::Url> {
+        let mut url = url::Url::parse(&self.endpoint).ok()?;
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .extend(segments);
+        Some(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        parse_weight, structured_reason, Capabilities, Client, ClientError, Health, Ping,
+        ServerRef, SetWeightRequest,
+    };
+    use crate::http::serve;
+    use crate::iam::Token;
+    use crate::token::CountingProvider;
+
+    fn test_token() -> Token {
+        Token {
+            access_token: "abc123".to_string(),
+            token_type: "Bearer".to_string(),
+            refresh_token: String::new(),
+            expiry: Instant::now() + Duration::from_secs(1200),
+        }
+    }
+
+    fn auth_header(client: &Client, name: &str) -> Option<String> {
+        let req = client
+            .authorize(
+                reqwest::blocking::Client::new().get("http://localhost/"),
+                &test_token(),
+            )
+            .build()
+            .unwrap();
+
+        req.headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn extra_headers() {
+        let header = |s| crate::http::parse_header(s).unwrap();
+        let (tenant, acme) = header("X-Tenant-ID: acme");
+        let (auth, custom) = header("Authorization: Basic xyz");
+        let client = Client::new("be/srv", Some("http://localhost"))
+            .with_header(tenant, acme)
+            .with_header(auth, custom);
+
+        let mut req = client
+            .authorize(
+                reqwest::blocking::Client::new().get("http://localhost/"),
+                &test_token(),
+            )
+            .build()
+            .unwrap();
+        client.add_headers(&mut req);
+
+        assert_eq!(req.headers()["X-Tenant-ID"], "acme");
+        assert_eq!(req.headers().get_all("Authorization").iter().count(), 1);
+        assert_eq!(req.headers()["Authorization"], "Basic xyz");
+    }
+
+    #[test]
+    fn default_auth_header() {
+        let client = Client::new("be/srv", Some("http://localhost"));
+        assert_eq!(
+            auth_header(&client, "Authorization").as_deref(),
+            Some("Bearer abc123")
+        );
+    }
+
+    #[test]
+    fn custom_auth_header() {
+        let client = Client::new("be/srv", Some("http://localhost"))
+            .with_bearer_header_name("X-Auth-Token", "Token ");
+        assert_eq!(
+            auth_header(&client, "X-Auth-Token").as_deref(),
+            Some("Token abc123")
+        );
+        assert_eq!(auth_header(&client, "Authorization"), None);
+    }
+
+    #[test]
+    fn set_weight_request_body() {
+        let expected = SetWeightRequest {
+            weight: 50,
+            reason: Some("rebalance".to_string()),
+        };
+        let body = serde_json::to_string(&expected).unwrap();
+        assert_eq!(body, r#"{"weight":50,"reason":"rebalance"}"#);
+
+        let parsed: SetWeightRequest = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed, expected);
+
+        let unreasoned = SetWeightRequest {
+            weight: 0,
+            reason: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&unreasoned).unwrap(),
+            r#"{"weight":0}"#
+        );
+    }
+
+    #[test]
+    fn weight_step_check() {
+        let (endpoint, server) = serve(3, |r| {
+            if r.contains("web3") {
+                "draining".to_string()
+            } else {
+                r#"{"weight":10}"#.to_string()
+            }
+        });
+        let client = Client::for_endpoint(&endpoint)
+            .with_token_provider(Arc::new(CountingProvider::default()));
+
+        client.check_weight_step("web/web1", 60, 50).unwrap();
+        let err = client.check_weight_step("web/web2", 61, 50).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("refusing to change web/web2 from weight 10 to 61"));
+        let err = client.check_weight_step("web/web3", 10, 50).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot read the current weight of web/web3: draining"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn one_token_for_get_then_set() {
+        let (endpoint, server) = serve(2, |_| r#"{"weight":10}"#.to_string());
+        let provider = Arc::new(CountingProvider::default());
+        let client = Client::for_endpoint(&endpoint).with_token_provider(provider.clone());
+
+        client.get_weight("web/web1").unwrap();
+        client.set_weight("web/web1", 20, "rebalance").unwrap();
+        assert_eq!(
+            provider.fetches.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+
+        let requests = server.join().unwrap();
+        assert!(requests.iter().all(|r| r.contains("Bearer test-token")));
+    }
+
+    #[test]
+    fn rotated_token_file_picked_up() {
+        let path = std::env::temp_dir().join(format!("hapctl-client-token-{}", std::process::id()));
+        std::fs::write(&path, "first\n").unwrap();
+        let (endpoint, server) = serve(2, |_| r#"{"weight":10}"#.to_string());
+        let client = Client::for_endpoint(&endpoint)
+            .with_token_provider(Arc::new(crate::token::FileTokenProvider::new(&path)));
+
+        client.get_weight("web/web1").unwrap();
+        std::fs::write(&path, "second\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        client.set_weight("web/web1", 20, "rebalance").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Bearer first"));
+        assert!(requests[1].contains("Bearer second"));
+    }
+
+    #[test]
+    fn shared_token_provider() {
+        let first = Client::for_endpoint("https://example.net").with_token_provider(Arc::new(
+            crate::token::FileTokenProvider::new("/nonexistent"),
+        ));
+        let provider = first.token_provider().unwrap();
+        assert!(Arc::ptr_eq(&provider, &first.token_provider().unwrap()));
+
+        let second = Client::for_endpoint("https://example.net").with_token_provider(provider);
+        assert!(Arc::ptr_eq(
+            &first.token_provider().unwrap(),
+            &second.token_provider().unwrap()
+        ));
+    }
+
+    #[test]
+    fn weight_from_response() {
+        assert_eq!(parse_weight(r#"{"weight":50}"#), Some(50));
+        assert_eq!(parse_weight(r#"{"weight":0,"reason":"drain"}"#), Some(0));
+        assert_eq!(parse_weight("12\n"), Some(12));
+        assert_eq!(parse_weight(r#"{"state":"ready"}"#), None);
+        assert_eq!(parse_weight("not json"), None);
+    }
+
+    #[test]
+    fn default_reason() {
+        let client = Client::for_endpoint("https://example.net");
+        assert_eq!(client.reason(None), None);
+        assert_eq!(client.reason(Some("")), Some(""));
+
+        let client = client.with_default_reason("automated rebalancing");
+        assert_eq!(client.reason(None), Some("automated rebalancing"));
+        assert_eq!(client.reason(Some("")), Some("automated rebalancing"));
+        assert_eq!(client.reason(Some("maint")), Some("maint"));
+    }
+
+    #[test]
+    fn server_ref_parsing() {
+        let server: ServerRef = "web/web1".parse().unwrap();
+        assert_eq!(server.backend, "web");
+        assert_eq!(server.server, "web1");
+        assert_eq!(server.to_string(), "web/web1");
+
+        assert!("web".parse::<ServerRef>().is_err());
+        assert!("a/b/c".parse::<ServerRef>().is_err());
+
+        let server = ServerRef::parse_with("web:web1", ':').unwrap();
+        assert_eq!(server.backend, "web");
+        assert_eq!(server.server, "web1");
+        assert!(ServerRef::parse_with("web/web1", ':').is_err());
+        assert!(ServerRef::parse_with("a:b:c", ':').is_err());
+    }
+
+    #[test]
+    fn error_variants() {
+        let client = Client::new("web/web1", Some("https://example.net"));
+        assert!(matches!(
+            client.get_weight("web"),
+            Err(ClientError::InvalidServerName(name)) if name == "web"
+        ));
+        assert!(matches!(
+            client.set_weight("web/web1", 300, "r"),
+            Err(ClientError::WeightOutOfRange {
+                weight: 300,
+                max: 256
+            })
+        ));
+
+        let boxed: Box<dyn std::error::Error> = crate::iam::IamError::MissingApiKey.into();
+        assert!(matches!(
+            ClientError::from(boxed),
+            ClientError::Iam(crate::iam::IamError::MissingApiKey)
+        ));
+        let boxed: Box<dyn std::error::Error> = "token file is empty".into();
+        assert_eq!(ClientError::from(boxed).to_string(), "token file is empty");
+
+        // Callers move errors across threads and into other error types.
+        fn send_sync<E: Send + Sync + 'static>(_: E) {}
+        send_sync(ClientError::from("boom".to_string()));
+    }
+
+    #[test]
+    fn capabilities_parsing() {
+        let caps: Capabilities =
+            serde_json::from_str(r#"{"version":"1.4","features":["weight","state"]}"#).unwrap();
+        assert!(caps.supports("state"));
+        assert!(!caps.supports("stats"));
+
+        let caps: Capabilities = serde_json::from_str("{}").unwrap();
+        assert_eq!(caps.version, None);
+        assert!(Capabilities::baseline().supports("weight"));
+    }
+
+    #[test]
+    fn ping_checks_auth() {
+        let (endpoint, server) = serve(2, |_| "{}".to_string());
+        let client = Client::for_endpoint(&endpoint)
+            .with_token_provider(Arc::new(CountingProvider::default()));
+        let ping = client.ping().unwrap();
+        assert!(ping.healthy());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /healthz "));
+        assert!(requests[1].starts_with("GET /v1/capabilities "));
+        assert!(requests[1].contains("Bearer test-token"));
+
+        let status = |healthz: u16, auth: u16| Ping {
+            endpoint: endpoint.clone(),
+            status: reqwest::StatusCode::from_u16(healthz).unwrap(),
+            auth_status: reqwest::StatusCode::from_u16(auth).unwrap(),
+            auth_elapsed: Duration::ZERO,
+            request_elapsed: Duration::ZERO,
+        };
+        // No /healthz or no capabilities route is fine, a rejected token is not.
+        assert!(status(404, 200).healthy());
+        assert!(status(200, 404).healthy());
+        assert!(!status(200, 401).auth_ok());
+        assert!(!status(200, 403).healthy());
+        assert!(!status(503, 200).healthy());
+        // A failing gateway says nothing about the token.
+        assert!(!status(200, 500).auth_ok());
+        assert!(!status(200, 502).healthy());
+        assert!(status(200, 502)
+            .to_string()
+            .contains("auth unknown (HTTP 502)"));
+        assert!(status(200, 401)
+            .to_string()
+            .contains("auth rejected (HTTP 401)"));
+        assert!(status(404, 200).to_string().contains("no /healthz"));
+    }
+
+    #[test]
+    fn health_reports() {
+        let ok = reqwest::StatusCode::OK;
+        let health = Health::parse(ok, r#"{"status":"ok","version":"1.4","uptime":3600}"#);
+        assert_eq!(health.status.as_deref(), Some("ok"));
+        assert_eq!(health.version.as_deref(), Some("1.4"));
+        assert_eq!(health.uptime, Some(Duration::from_secs(3600)));
+        assert_eq!(
+            health.to_string(),
+            "HTTP 200, status ok, version 1.4, up 3600s"
+        );
+
+        let partial = Health::parse(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            r#"{"status":"degraded","checks":[]}"#,
+        );
+        assert!(!partial.healthy());
+        assert_eq!(partial.to_string(), "HTTP 503, status degraded");
+
+        assert_eq!(Health::parse(ok, "ok\n").status.as_deref(), Some("ok"));
+        assert_eq!(Health::parse(ok, "").to_string(), "HTTP 200");
+    }
+
+    #[test]
+    fn url_joining() {
+        let client = Client::for_endpoint("https://example.net/");
+        assert_eq!(client.url("/v1/status"), "https://example.net/v1/status");
+        assert_eq!(client.url("v1/status"), "https://example.net/v1/status");
+        assert_eq!(client.url("v1/x?a=1"), "https://example.net/v1/x?a=1");
+
+        let client = Client::for_endpoint("https://[2001:db8::1]:8443/api");
+        assert_eq!(
+            client.url("healthz"),
+            "https://[2001:db8::1]:8443/api/healthz"
+        );
+        let server: ServerRef = "web/web 1".parse().unwrap();
+        assert_eq!(
+            client.weight_url(&server),
+            "https://[2001:db8::1]:8443/api/v1/backends/web/servers/web%201/weight"
+        );
+
+        let client = Client::for_endpoint("https://example.net").with_api_base_path("/hapctl/v2/");
+        assert_eq!(
+            client.weight_url(&server),
+            "https://example.net/hapctl/v2/backends/web/servers/web%201/weight"
+        );
+        let client = Client::for_endpoint("https://example.net").with_api_base_path("");
+        assert_eq!(
+            client.api_url(&["capabilities"]),
+            "https://example.net/capabilities"
+        );
+    }
+
+    #[test]
+    fn correlation_header() {
+        let client = Client::for_endpoint("http://localhost")
+            .with_correlation_id("X-Correlation-ID", "change-42");
+        assert_eq!(
+            auth_header(&client, "X-Correlation-ID"),
+            Some("change-42".to_string())
+        );
+        assert_eq!(
+            auth_header(
+                &Client::for_endpoint("http://localhost"),
+                "X-Correlation-ID"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn structured_reason_format() {
+        let mut meta = BTreeMap::new();
+        assert_eq!(structured_reason("maint", &meta), "maint");
+
+        meta.insert("ticket".to_string(), "INC-1".to_string());
+        meta.insert("cr".to_string(), "CHG-2".to_string());
+        assert_eq!(
+            structured_reason("maint", &meta),
+            r#"{"reason":"maint","meta":{"cr":"CHG-2","ticket":"INC-1"}}"#
+        );
     }
 }
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Environment variable naming the config file to load.
+pub const CONFIG_ENV: &str = "HAPCTL_CONFIG";
+
+/// Environment variable naming the profile to use.
+pub const PROFILE_ENV: &str = "HAPCTL_PROFILE";
+
+/// Settings read from the TOML config file. Every key is optional.
+///
+/// ```toml
+/// # Ask for confirmation before setting a weight above this value.
+/// warn_above = 100
+/// region = "eu-de"
+///
+/// # Selected with --profile staging or HAPCTL_PROFILE=staging; its keys
+/// # override the top-level ones.
+/// [profiles.staging]
+/// endpoint = "https://hapctl-staging.example.net"
+/// timeout = 60
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub warn_above: Option<u32>,
+    /// Refuse to change a weight by more than this at once, whether by
+    /// `set-weight`, an `apply` entry or a `ramp` step, unless `--force` is
+    /// given.
+    pub max_weight_step: Option<u32>,
+    /// Reject reasons shorter than this many characters, not counting
+    /// surrounding whitespace.
+    pub min_reason_length: Option<usize>,
+    /// Append every weight change to this file, see `history::History`.
+    pub history_file: Option<PathBuf>,
+    /// Rotate the history file once it reaches this size, 1 MiB if unset.
+    pub history_max_bytes: Option<u64>,
+    /// OTLP/HTTP traces endpoint to export request spans to. Needs the `otel`
+    /// feature.
+    pub otlp_endpoint: Option<String>,
+    /// Add the operator's user name, host name and a timestamp to reasons.
+    pub reason_context: Option<bool>,
+    /// Header carrying the correlation ID, `X-Correlation-ID` if unset.
+    pub correlation_header: Option<String>,
+    /// Ping the endpoint before batch operations and abort if it fails.
+    pub preflight: Option<bool>,
+    /// Upper bound for weights, for balancers capped below 256.
+    pub weight_max: Option<u32>,
+    pub endpoint: Option<String>,
+    /// Path of the API below the endpoint, `/v1` if unset.
+    pub api_base_path: Option<String>,
+    /// Scheme for endpoints given without one, `https` if unset.
+    pub endpoint_scheme: Option<String>,
+    /// Separator between backend and server in server names, `/` if unset.
+    pub servername_delimiter: Option<char>,
+    pub region: Option<String>,
+    /// Set to false to require an endpoint or region instead of guessing the
+    /// endpoint from the server name.
+    pub auto_endpoint: Option<bool>,
+    /// Total request timeout, in seconds.
+    pub timeout: Option<u64>,
+    /// Connection timeout, in seconds.
+    pub connect_timeout: Option<u64>,
+    /// Timeout for pings and preflight health checks, in seconds.
+    pub ping_timeout: Option<u64>,
+    /// Endpoints for specific backends. Only an endpoint rule takes
+    /// precedence; a backend endpoint wins over the servername template and
+    /// any region, whether named in the server name or chosen with
+    /// `--region`.
+    pub backend_endpoints: BTreeMap<String, String>,
+    /// Pattern for structured server names, e.g. `{env}-{region}/{server}`,
+    /// whose env and region pick the endpoint.
+    pub servername_template: Option<String>,
+    /// Endpoints for the envs in `servername_template`; `{region}` is
+    /// replaced by the server name's region.
+    pub env_endpoints: BTreeMap<String, String>,
+    /// Name of the entry in `credentials` to authenticate with.
+    pub credential: Option<String>,
+    pub credentials: BTreeMap<String, Credential>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Where to find an IAM API key. Keys are only ever read from files, never
+/// stored in the config itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Credential {
+    /// File holding the API key; a leading `~/` is the home directory.
+    pub api_key_file: PathBuf,
+}
+
+/// Per-environment overrides for the top-level settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Profile {
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub credential: Option<String>,
+}
+
+impl Config {
+    /// Merge the named profile, or the one in `$HAPCTL_PROFILE`, over the
+    /// top-level settings. Without a profile the config is returned as is.
+    pub fn with_profile(self, name: Option<&str>) -> Result<Self> {
+        let from_env = std::env::var(PROFILE_ENV).ok();
+        self.with_named_profile(name.or(from_env.as_deref()))
+    }
+
+    /// Like `with_profile`, without looking at the environment.
+    pub fn with_named_profile(mut self, name: Option<&str>) -> Result<Self> {
+        let name = match name {
+            Some(n) => n.to_string(),
+            None => return Ok(self),
+        };
+
+        let profile = match self.profiles.get(&name) {
+            Some(p) => p.clone(),
+            None => {
+                let available: Vec<&str> = self.profiles.keys().map(|k| k.as_str()).collect();
+                return Err(format!(
+                    "unknown profile '{}', available profiles: {}",
+                    name,
+                    if available.is_empty() {
+                        "(none)".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                )
+                .into());
+            }
+        };
+
+        // A profile that picks an endpoint or a region replaces both, so a
+        // top-level endpoint does not shadow a profile's region.
+        if profile.endpoint.is_some() || profile.region.is_some() {
+            self.endpoint = profile.endpoint;
+            self.region = profile.region;
+        }
+        self.timeout = profile.timeout.or(self.timeout);
+        self.connect_timeout = profile.connect_timeout.or(self.connect_timeout);
+        self.credential = profile.credential.or(self.credential);
+
+        Ok(self)
+    }
+
+    /// The history file with a leading `~/` expanded, if one is configured.
+    pub fn history_path(&self) -> Option<PathBuf> {
+        self.history_file.as_deref().map(expand_home)
+    }
+
+    /// Read the API key of the named credential, or of the one selected by
+    /// the `credential` key when `name` is `None`. Returns `None` when no
+    /// credential is selected.
+    pub fn api_key(&self, name: Option<&str>) -> Result<Option<String>> {
+        let name = match name.or(self.credential.as_deref()) {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+
+        let credential = self.credentials.get(name).ok_or_else(|| {
+            let available: Vec<&str> = self.credentials.keys().map(|k| k.as_str()).collect();
+            format!(
+                "unknown credential '{}', available credentials: {}",
+                name,
+                if available.is_empty() {
+                    "(none)".to_string()
+                } else {
+                    available.join(", ")
+                }
+            )
+        })?;
+
+        let path = expand_home(&credential.api_key_file);
+        let key = std::fs::read_to_string(&path)
+            .map_err(|e| format!("reading API key file {}: {}", path.display(), e))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("API key file {} is empty", path.display()).into());
+        }
+
+        Ok(Some(key.to_string()))
+    }
+
+    /// Parse a TOML config, expanding `${VAR}` in its strings, see
+    /// `env::expand`.
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut value: toml::Value = toml::from_str(data)?;
+        crate::env::expand_toml(&mut value)?;
+        Ok(value.try_into()?)
+    }
+
+    /// Load the config from `path`, or from `$HAPCTL_CONFIG`, or from the
+    /// default location. An explicitly given file must exist; a missing
+    /// default file just means an empty config.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let from_env = std::env::var_os(CONFIG_ENV).map(PathBuf::from);
+        Self::load_path(path.or(from_env.as_deref()))
+    }
+
+    /// Like `load`, without looking at the environment.
+    pub fn load_path(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => match default_path() {
+                Some(p) if p.exists() => p,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let data = std::fs::read_to_string(&path)
+            .map_err(|e| format!("reading config {}: {}", path.display(), e))?;
+        Self::parse(&data).map_err(|e| format!("parsing config {}: {}", path.display(), e).into())
+    }
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/hapctl/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+
+    Some(base.join("hapctl").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn empty_config() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.warn_above, None);
+    }
+
+    #[test]
+    fn profile_overrides() {
+        let config = Config::parse(
+            r#"
+            endpoint = "https://prod.example"
+            timeout = 30
+
+            [profiles.staging]
+            region = "eu-de"
+            timeout = 60
+
+            [profiles.slow]
+            connect_timeout = 20
+            "#,
+        )
+        .unwrap();
+
+        let staging = config.clone().with_profile(Some("staging")).unwrap();
+        assert_eq!(staging.endpoint, None);
+        assert_eq!(staging.region.as_deref(), Some("eu-de"));
+        assert_eq!(staging.timeout, Some(60));
+
+        let slow = config.clone().with_profile(Some("slow")).unwrap();
+        assert_eq!(slow.endpoint.as_deref(), Some("https://prod.example"));
+        assert_eq!(slow.timeout, Some(30));
+        assert_eq!(slow.connect_timeout, Some(20));
+
+        let err = config.with_profile(Some("prod")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown profile 'prod', available profiles: slow, staging"
+        );
+    }
+
+    #[test]
+    fn auto_endpoint() {
+        assert_eq!(Config::parse("").unwrap().auto_endpoint, None);
+        assert_eq!(
+            Config::parse("auto_endpoint = false")
+                .unwrap()
+                .auto_endpoint,
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn backend_endpoints() {
+        let config = Config::parse(
+            r#"
+            [backend_endpoints]
+            backend-x = "https://hapctl-x.example.net"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.backend_endpoints["backend-x"],
+            "https://hapctl-x.example.net"
+        );
+    }
+
+    #[test]
+    fn credentials() {
+        let key_file = std::env::temp_dir().join(format!("hapctl-key-{}", std::process::id()));
+        std::fs::write(&key_file, "prod-key\n").unwrap();
+
+        let config = Config::parse(&format!(
+            r#"
+            [credentials.prod]
+            api_key_file = "{}"
+
+            [profiles.prod]
+            credential = "prod"
+            "#,
+            key_file.display()
+        ))
+        .unwrap();
+
+        assert_eq!(config.api_key(None).unwrap(), None);
+        assert_eq!(
+            config.api_key(Some("prod")).unwrap().as_deref(),
+            Some("prod-key")
+        );
+        let prod = config.clone().with_profile(Some("prod")).unwrap();
+        assert_eq!(prod.api_key(None).unwrap().as_deref(), Some("prod-key"));
+        assert_eq!(
+            config.api_key(Some("dev")).unwrap_err().to_string(),
+            "unknown credential 'dev', available credentials: prod"
+        );
+
+        std::fs::remove_file(&key_file).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            warn_above: Some(200),
+            reason_context: Some(true),
+            servername_delimiter: Some(':'),
+            ..Config::default()
+        };
+        let data = toml::to_string(&config).unwrap();
+        assert_eq!(Config::parse(&data).unwrap(), config);
+
+        assert!(Config::parse(r#"servername_delimiter = "::""#).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+/// Expand `${VAR}` references in `input` with `lookup`. `${VAR:-default}`
+/// uses `default` when the variable is unset or empty, and `$$` is a
+/// literal `$`. Any other `$` is kept as is.
+pub fn expand_with<F>(input: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated '${{' in '{}'", input))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            if name.is_empty() {
+                return Err(format!("empty variable name in '{}'", input));
+            }
+
+            match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => out.push_str(default),
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(format!("environment variable '{}' is not set", name));
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Expand `${VAR}` references in `input` from the environment, see
+/// `expand_with`.
+pub fn expand(input: &str) -> Result<String, String> {
+    expand_with(input, |name| std::env::var(name).ok())
+}
+
+/// Expand every string in a parsed TOML document, leaving keys and other
+/// values alone.
+pub fn expand_toml(value: &mut toml::Value) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) => *s = expand(s)?,
+        toml::Value::Array(items) => items.iter_mut().try_for_each(expand_toml)?,
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, v)| expand_toml(v))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Like `expand_toml`, for JSON.
+pub fn expand_json(value: &mut serde_json::Value) -> Result<(), String> {
+    match value {
+        serde_json::Value::String(s) => *s = expand(s)?,
+        serde_json::Value::Array(items) => items.iter_mut().try_for_each(expand_json)?,
+        serde_json::Value::Object(object) => object.values_mut().try_for_each(expand_json)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_with;
+
+    #[test]
+    fn expansion() {
+        let lookup = |name: &str| match name {
+            "REGION" => Some("us-south".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |s: &str| expand_with(s, lookup);
+
+        assert_eq!(
+            expand("https://${REGION}.example.net").unwrap(),
+            "https://us-south.example.net"
+        );
+        assert_eq!(
+            expand("${MISSING:-/tmp/keys}/prod").unwrap(),
+            "/tmp/keys/prod"
+        );
+        assert_eq!(expand("${EMPTY:-fallback}").unwrap(), "fallback");
+        assert_eq!(expand("[${EMPTY}]").unwrap(), "[]");
+        assert_eq!(expand("${REGION:-}").unwrap(), "us-south");
+        assert_eq!(expand("$$HOME costs $5").unwrap(), "$HOME costs $5");
+
+        let err = expand("key ${MISSING}").unwrap_err();
+        assert_eq!(err, "environment variable 'MISSING' is not set");
+        assert!(expand("${REGION").is_err());
+        assert!(expand("${}").is_err());
+    }
+}
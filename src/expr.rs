@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// An arithmetic expression over numbers and named variables, such as
+/// `base * canary_fraction`. Supports `+ - * /`, unary minus and
+/// parentheses with the usual precedence.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Var(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(i, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = i;
+            while let Some(&(j, d)) = chars.peek() {
+                if !(d.is_ascii_digit() || d == '.') {
+                    break;
+                }
+                end = j + d.len_utf8();
+                chars.next();
+            }
+            let number = &input[i..end];
+            tokens.push(Token::Number(
+                number
+                    .parse()
+                    .map_err(|_| format!("invalid number '{}'", number))?,
+            ));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = i;
+            while let Some(&(j, d)) = chars.peek() {
+                if !(d.is_alphanumeric() || d == '_') {
+                    break;
+                }
+                end = j + d.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(input[i..end].to_string()));
+        } else {
+            tokens.push(match c {
+                '+' | '-' | '*' | '/' => Token::Op(c),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                _ => return Err(format!("unexpected character '{}'", c).into()),
+            });
+            chars.next();
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Deepest nesting of unary minus and parentheses the parser accepts, so a
+/// hostile plan file cannot overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    /// Run `f` one nesting level deeper, failing past `MAX_DEPTH`.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("expression nested deeper than {} levels", MAX_DEPTH).into());
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Expr> {
+        let mut lhs = self.product()?;
+        while let Some(Token::Op(c @ ('+' | '-'))) = self.peek().cloned() {
+            self.next();
+            let op = if c == '+' { Op::Add } else { Op::Sub };
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.product()?));
+        }
+        Ok(lhs)
+    }
+
+    // product := unary (('*' | '/') unary)*
+    fn product(&mut self) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(c @ ('*' | '/'))) = self.peek().cloned() {
+            self.next();
+            let op = if c == '*' { Op::Mul } else { Op::Div };
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    // unary := '-' unary | atom
+    fn unary(&mut self) -> Result<Expr> {
+        if let Some(Token::Op('-')) = self.peek() {
+            self.next();
+            return Ok(Expr::Neg(Box::new(self.nested(Self::unary)?)));
+        }
+        self.atom()
+    }
+
+    // atom := number | ident | '(' sum ')'
+    fn atom(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => Ok(Expr::Var(name)),
+            Some(Token::Open) => {
+                let inner = self.nested(Self::sum)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err("missing ')'".into()),
+                }
+            }
+            Some(t) => Err(format!("unexpected {:?}", t).into()),
+            None => Err("unexpected end of expression".into()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.sum()?;
+        if let Some(t) = parser.peek() {
+            return Err(format!("unexpected {:?}", t).into());
+        }
+        Ok(expr)
+    }
+
+    /// Evaluate with the given variables; unknown variables and division by
+    /// zero are errors.
+    pub fn eval(&self, vars: &BTreeMap<String, f64>) -> Result<f64> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Var(name) => *vars
+                .get(name)
+                .ok_or_else(|| format!("unknown variable '{}'", name))?,
+            Expr::Neg(e) => -e.eval(vars)?,
+            Expr::Binary(lhs, op, rhs) => {
+                let (a, b) = (lhs.eval(vars)?, rhs.eval(vars)?);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div if b == 0.0 => return Err("division by zero".into()),
+                    Op::Div => a / b,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expr;
+
+    use std::collections::BTreeMap;
+
+    fn eval(input: &str) -> f64 {
+        let vars = BTreeMap::from([("base".to_string(), 100.0), ("frac".to_string(), 0.25)]);
+        Expr::parse(input).unwrap().eval(&vars).unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("base * frac"), 25.0);
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("base - -base / 4"), 125.0);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Expr::parse("base *").is_err());
+        assert!(Expr::parse("(base").is_err());
+        assert!(Expr::parse("base $ 2").is_err());
+        assert!(Expr::parse("1 2").is_err());
+
+        let vars = BTreeMap::new();
+        let err = Expr::parse("x + 1").unwrap().eval(&vars).unwrap_err();
+        assert_eq!(err.to_string(), "unknown variable 'x'");
+        assert!(Expr::parse("1 / 0").unwrap().eval(&vars).is_err());
+
+        assert_eq!(eval(&format!("{}1", "-".repeat(64))), 1.0);
+        let err = Expr::parse(&format!("{}1", "-".repeat(300_000))).unwrap_err();
+        assert_eq!(err.to_string(), "expression nested deeper than 64 levels");
+        let parens = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(Expr::parse(&parens).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::{parse_weight, Client, ServerRef};
+use crate::reason::{operator_context, utc_timestamp};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Size at which the history file is rotated unless configured otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+/// One weight change made by this tool.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Change {
+    /// When the change was made, UTC, RFC 3339.
+    pub at: String,
+    pub server: String,
+    /// The weight before the change, if it could be read.
+    pub old_weight: Option<u32>,
+    pub new_weight: u32,
+    pub reason: Option<String>,
+    /// `user@host` of the operator, as far as it is known.
+    pub operator: Option<String>,
+}
+
+impl Change {
+    /// A change made now by the current operator.
+    pub fn now(
+        server: &str,
+        old_weight: Option<u32>,
+        new_weight: u32,
+        reason: Option<&str>,
+    ) -> Self {
+        let context = operator_context();
+        let operator = match (context.get("user"), context.get("host")) {
+            (Some(user), Some(host)) => Some(format!("{}@{}", user, host)),
+            (Some(user), None) => Some(user.clone()),
+            (None, Some(host)) => Some(format!("@{}", host)),
+            (None, None) => None,
+        };
+
+        Change {
+            at: utc_timestamp(SystemTime::now()),
+            server: server.to_string(),
+            old_weight,
+            new_weight,
+            reason: reason.map(String::from),
+            operator,
+        }
+    }
+
+    /// Run `set`, which moves `name` to `weight` through `client`, and
+    /// return its result with the change to record. The old weight is read
+    /// first, but failing to read it does not stop the change.
+    pub fn track<T, F>(
+        client: &Client,
+        name: &str,
+        weight: u32,
+        reason: Option<&str>,
+        set: F,
+    ) -> Result<(T, Change)>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let old = client
+            .get_weight(name)
+            .ok()
+            .and_then(|body| parse_weight(&body));
+        let result = set()?;
+
+        let server = name
+            .parse::<ServerRef>()
+            .map(|s| s.to_string())
+            .unwrap_or_else(|_| name.to_string());
+        Ok((result, Change::now(&server, old, weight, reason)))
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let old = self
+            .old_weight
+            .map(|w| w.to_string())
+            .unwrap_or_else(|| "?".to_string());
+        write!(
+            f,
+            "{} {} {} -> {}",
+            self.at, self.server, old, self.new_weight
+        )?;
+        if let Some(operator) = &self.operator {
+            write!(f, " by {}", operator)?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// An append-only local log of weight changes, one JSON object per line.
+///
+/// Once the file would grow past its size limit it is moved to `<path>.1`,
+/// replacing the previous one, and a new file is started.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl History {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        History {
+            path: path.into(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+
+    /// Rotate the file once it would grow past `bytes`.
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = bytes;
+        self
+    }
+
+    fn rotated(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".1");
+        PathBuf::from(name)
+    }
+
+    pub fn record(&self, change: &Change) -> Result<()> {
+        let line = serde_json::to_string(change)? + "\n";
+        let context = |e: std::io::Error| format!("writing history {}: {}", self.path.display(), e);
+
+        let size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            std::fs::rename(&self.path, self.rotated()).map_err(context)?;
+        }
+
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(context)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(context)?;
+        file.write_all(line.as_bytes()).map_err(context)?;
+        Ok(())
+    }
+
+    /// The last `limit` changes, oldest first, optionally only those of
+    /// `server`. Lines that cannot be parsed are skipped.
+    pub fn read(&self, server: Option<&str>, limit: usize) -> Result<Vec<Change>> {
+        let mut changes = Vec::new();
+        for path in [self.rotated(), self.path.clone()] {
+            changes.extend(read_file(&path)?);
+        }
+
+        changes.retain(|c| server.is_none_or(|s| c.server == s));
+        let skip = changes.len().saturating_sub(limit);
+        Ok(changes.split_off(skip))
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<Change>> {
+    let file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("reading history {}: {}", path.display(), e).into()),
+    };
+
+    let mut changes = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(change) = serde_json::from_str(&line?) {
+            changes.push(change);
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, History};
+
+    use std::sync::Arc;
+
+    use crate::client::Client;
+    use crate::http::serve;
+    use crate::token::CountingProvider;
+
+    #[test]
+    fn record_filter_and_rotate() {
+        let change = |server: &str, weight: u32| Change {
+            at: "2024-02-29T12:34:56Z".to_string(),
+            server: server.to_string(),
+            old_weight: Some(weight - 1),
+            new_weight: weight,
+            reason: Some("rebalance".to_string()),
+            operator: None,
+        };
+        let line_len = serde_json::to_string(&change("web/web1", 1)).unwrap().len() as u64 + 1;
+
+        let dir = std::env::temp_dir().join(format!("hapctl-history-{}", std::process::id()));
+        let history = History::new(dir.join("history.jsonl")).with_max_bytes(3 * line_len);
+
+        for (i, server) in ["web/web1", "web/web2", "web/web1", "web/web1"]
+            .iter()
+            .enumerate()
+        {
+            history.record(&change(server, i as u32 + 1)).unwrap();
+        }
+        assert!(dir.join("history.jsonl.1").exists());
+
+        let all = history.read(None, 10).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[0].server, "web/web1");
+        assert_eq!(all[3].new_weight, 4);
+
+        let web1 = history.read(Some("web/web1"), 2).unwrap();
+        let weights: Vec<u32> = web1.iter().map(|c| c.new_weight).collect();
+        assert_eq!(weights, vec![3, 4]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeated_write_recorded() {
+        let written = std::sync::Mutex::new(40);
+        let (endpoint, server) = serve(5, move |request| {
+            let mut weight = written.lock().unwrap();
+            if request.starts_with("POST") {
+                *weight = 60;
+            }
+            format!(r#"{{"weight":{}}}"#, weight)
+        });
+        let client = Client::for_endpoint(&endpoint)
+            .with_token_provider(Arc::new(CountingProvider::default()));
+
+        let (result, change) = Change::track(&client, "web/web1", 60, Some("canary"), || {
+            Ok(client.set_weight_repeated("web/web1", 60, Some("canary"), 3)?)
+        })
+        .unwrap();
+        assert_eq!(result.weight, 60);
+        assert_eq!(change.old_weight, Some(40));
+        assert_eq!(change.new_weight, 60);
+
+        let dir =
+            std::env::temp_dir().join(format!("hapctl-history-repeat-{}", std::process::id()));
+        let history = History::new(dir.join("history.jsonl"));
+        history.record(&change).unwrap();
+        let changes = history.read(Some("web/web1"), 10).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].reason.as_deref(), Some("canary"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The old weight read, three writes with one key, the check read.
+        let requests = server.join().unwrap();
+        let keys: Vec<&str> = requests
+            .iter()
+            .filter(|r| r.starts_with("POST"))
+            .filter_map(|r| {
+                r.lines()
+                    .find(|l| l.to_ascii_lowercase().starts_with("idempotency-key:"))
+            })
+            .collect();
+        assert_eq!(keys.len(), 3);
+        assert!(keys.iter().all(|k| *k == keys[0]));
+    }
+
+    #[test]
+    fn display() {
+        let change = Change {
+            at: "2024-02-29T12:34:56Z".to_string(),
+            server: "web/web1".to_string(),
+            old_weight: None,
+            new_weight: 50,
+            reason: Some("canary".to_string()),
+            operator: Some("alice@ops1".to_string()),
+        };
+        assert_eq!(
+            change.to_string(),
+            "2024-02-29T12:34:56Z web/web1 ? -> 50 by alice@ops1: canary"
+        );
+    }
+}
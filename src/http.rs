@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::reason::utc_timestamp;
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timeout for health checks, kept short so an unhealthy endpoint fails a
+/// preflight quickly however long the operation timeout is.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest response body read before giving up, unless changed with
+/// `with_max_response_size` on the API or IAM client.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+static SHARED: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+
+static TRANSCRIPT: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Body fields and form parameters whose values never go into a transcript.
+const SECRET_FIELDS: [&str; 3] = ["apikey", "access_token", "refresh_token"];
+
+/// Install `client` as the connection pool used by every `hapctl::Client` and
+/// `iam::Client` that was not given its own with `with_http_client`.
+///
+/// Meant to be called once at startup, after settings are resolved; later
+/// calls are rejected.
+pub fn set_shared_client(client: reqwest::blocking::Client) -> Result<(), &'static str> {
+    SHARED
+        .set(client)
+        .map_err(|_| "shared HTTP client already set")
+}
+
+/// Pick the HTTP client for a request: an explicitly injected one, then the
+/// shared one, and only then a new client built with the given timeouts.
+pub(crate) fn client_for(
+    own: &Option<reqwest::blocking::Client>,
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> reqwest::Result<reqwest::blocking::Client> {
+    match own.as_ref().or_else(|| SHARED.get()) {
+        Some(c) => Ok(c.clone()),
+        None => build_client(connect_timeout, timeout),
+    }
+}
+
+/// Build a blocking client with the given timeouts and this crate's standard
+/// settings.
+pub fn build_client(
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> reqwest::Result<reqwest::blocking::Client> {
+    let builder = reqwest::blocking::Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout);
+
+    // With these enabled reqwest advertises them in Accept-Encoding and
+    // decodes compressed bodies; uncompressed responses are unaffected.
+    #[cfg(feature = "compression")]
+    let builder = builder.gzip(true).brotli(true).deflate(true);
+
+    builder.build()
+}
+
+/// Scheme assumed for endpoints given as a bare host.
+pub const DEFAULT_SCHEME: &str = "https";
+
+/// Turn a user-supplied endpoint into a base URL, prepending
+/// `<default_scheme>://` when it has no scheme, e.g. `hapctl.internal`
+/// becomes `https://hapctl.internal`. Anything that still is not an http or
+/// https URL with a host is an error.
+pub fn normalize_endpoint(
+    endpoint: &str,
+    default_scheme: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let endpoint = endpoint.trim();
+    let full = if endpoint.contains("://") {
+        endpoint.to_string()
+    } else {
+        format!("{}://{}", default_scheme, endpoint)
+    };
+
+    let url = url::Url::parse(&full).map_err(|e| {
+        let authority = full.split("://").nth(1).unwrap_or("");
+        let authority = authority.split('/').next().unwrap_or("");
+        if authority.matches(':').count() > 1 && !authority.starts_with('[') {
+            format!(
+                "invalid endpoint '{}': IPv6 addresses must be in brackets, e.g. https://[2001:db8::1]:8443",
+                endpoint
+            )
+        } else {
+            format!("invalid endpoint '{}': {}", endpoint, e)
+        }
+    })?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "invalid endpoint '{}': scheme must be http or https",
+            endpoint
+        )
+        .into());
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("invalid endpoint '{}': no host", endpoint).into());
+    }
+
+    Ok(full.trim_end_matches('/').to_string())
+}
+
+/// Parse a `Name: value` header given on the command line.
+pub fn parse_header(
+    header: &str,
+) -> Result<(reqwest::header::HeaderName, reqwest::header::HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{}': expected 'Name: value'", header))?;
+    let name = name.trim();
+    let parsed_name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name))?;
+    let value = reqwest::header::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value for header '{}'", name))?;
+    Ok((parsed_name, value))
+}
+
+/// Why a response body could not be read.
+#[derive(Debug)]
+pub(crate) enum BodyError {
+    /// The body is longer than the limit.
+    TooLarge,
+    Transport(reqwest::Error),
+    Io(std::io::Error),
+}
+
+/// Read the body of `resp` as text, failing as soon as it turns out to be
+/// longer than `limit` bytes instead of buffering all of it.
+pub(crate) fn read_body(
+    resp: reqwest::blocking::Response,
+    limit: usize,
+) -> Result<String, BodyError> {
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(BodyError::TooLarge);
+    }
+
+    let mut body = Vec::new();
+    resp.take(limit as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(body_error)?;
+    if body.len() > limit {
+        return Err(BodyError::TooLarge);
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Unwrap the reqwest error a failed body read usually carries, so it can be
+/// described like a send error.
+fn body_error(e: std::io::Error) -> BodyError {
+    let kind = e.kind();
+    match e.into_inner() {
+        Some(inner) => match inner.downcast::<reqwest::Error>() {
+            Ok(e) => BodyError::Transport(*e),
+            Err(inner) => BodyError::Io(std::io::Error::new(kind, inner)),
+        },
+        None => BodyError::Io(kind.into()),
+    }
+}
+
+/// Turn a reqwest send error into a message that tells an unreachable
+/// endpoint apart from a slow one.
+pub(crate) fn describe_error(
+    e: &reqwest::Error,
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> String {
+    let url = e
+        .url()
+        .map(|u| u.to_string())
+        .unwrap_or_else(|| "endpoint".to_string());
+
+    if e.is_connect() {
+        if e.is_timeout() {
+            format!(
+                "could not connect to {} within {}s (endpoint unreachable?)",
+                url,
+                connect_timeout.as_secs()
+            )
+        } else {
+            format!("could not connect to {}: {}", url, e)
+        }
+    } else if e.is_timeout() {
+        format!(
+            "request to {} did not complete within {}s (endpoint slow?)",
+            url,
+            timeout.as_secs()
+        )
+    } else {
+        e.to_string()
+    }
+}
+
+/// Timing breakdown for one request, logged at info level by `--debug-net`.
+///
+/// reqwest does not expose connection phase timings, so DNS is measured with a
+/// separate lookup of the host up front, and connect, TLS and time to first
+/// byte are reported together as the time until response headers arrive.
+pub(crate) struct NetTimer {
+    url: String,
+    dns: Option<Duration>,
+    start: Instant,
+    headers: Option<Duration>,
+}
+
+impl NetTimer {
+    pub(crate) fn start(url: &url::Url) -> Self {
+        let dns_start = Instant::now();
+        let dns = url.socket_addrs(|| None).ok().map(|_| dns_start.elapsed());
+
+        NetTimer {
+            url: format!("{}{}", url.origin().ascii_serialization(), url.path()),
+            dns,
+            start: Instant::now(),
+            headers: None,
+        }
+    }
+
+    /// Mark the arrival of the response headers.
+    pub(crate) fn headers_received(&mut self) {
+        self.headers = Some(self.start.elapsed());
+    }
+
+    pub(crate) fn finish(self, status: reqwest::StatusCode) {
+        let ms = |d: Option<Duration>| {
+            d.map(|d| format!("{}ms", d.as_millis()))
+                .unwrap_or_else(|| "n/a".to_string())
+        };
+
+        info!(
+            "net: {} status={} dns={} connect+tls+ttfb={} total={}",
+            self.url,
+            status.as_u16(),
+            ms(self.dns),
+            ms(self.headers),
+            ms(Some(self.start.elapsed()))
+        );
+    }
+}
+
+/// Append a JSON record of every HTTP request made from now on (IAM and API)
+/// to the file at `path`, one object per line. Credentials in headers and
+/// bodies are redacted. Must be called before any request is made.
+pub fn set_transcript<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("opening transcript {}: {}", path.display(), e))?;
+
+    TRANSCRIPT
+        .set(Mutex::new(file))
+        .map_err(|_| "transcript already set".into())
+}
+
+#[derive(Debug, Serialize)]
+struct TranscriptRecord {
+    time: String,
+    method: String,
+    url: String,
+    request_headers: BTreeMap<String, String>,
+    request_body: Option<String>,
+    status: Option<u16>,
+    response_headers: BTreeMap<String, String>,
+    response_body: Option<String>,
+    error: Option<String>,
+    elapsed_ms: u128,
+}
+
+/// One request/response exchange being recorded for `set_transcript`.
+pub(crate) struct Transcript {
+    record: TranscriptRecord,
+    start: Instant,
+}
+
+impl Transcript {
+    /// Start recording `request`, or return `None` when no transcript is
+    /// being written. Values of `secret_headers` and of
+    /// `SENSITIVE_HEADERS` (matched case-insensitively) are redacted.
+    pub(crate) fn start(
+        request: &reqwest::blocking::Request,
+        secret_headers: &[&str],
+    ) -> Option<Self> {
+        TRANSCRIPT.get()?;
+
+        let request_headers = redact_headers(request.headers(), secret_headers);
+        let request_body = request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .map(|b| redact_body(&String::from_utf8_lossy(b)));
+
+        Some(Transcript {
+            record: TranscriptRecord {
+                time: utc_timestamp(SystemTime::now()),
+                method: request.method().to_string(),
+                url: request.url().to_string(),
+                request_headers,
+                request_body,
+                status: None,
+                response_headers: BTreeMap::new(),
+                response_body: None,
+                error: None,
+                elapsed_ms: 0,
+            },
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn response(
+        &mut self,
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+    ) {
+        self.record.status = Some(status.as_u16());
+        self.record.response_headers = redact_headers(headers, &[]);
+    }
+
+    /// Write the record, with the response body if it was read.
+    pub(crate) fn finish(mut self, body: Option<&str>) {
+        self.record.response_body = body.map(redact_body);
+        self.write();
+    }
+
+    pub(crate) fn failed(mut self, error: &str) {
+        self.record.error = Some(error.to_string());
+        self.write();
+    }
+
+    fn write(mut self) {
+        self.record.elapsed_ms = self.start.elapsed().as_millis();
+
+        let file = match TRANSCRIPT.get() {
+            Some(f) => f,
+            None => return,
+        };
+        let line = serde_json::to_string(&self.record).expect("serializing transcript record");
+        let mut file = file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("writing transcript failed: {}", e);
+        }
+    }
+}
+
+/// Headers whose values never go into a transcript, in either direction.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+];
+
+/// `headers` as strings, with the values of `secret` and of
+/// `SENSITIVE_HEADERS` replaced by `<redacted>`.
+fn redact_headers(
+    headers: &reqwest::header::HeaderMap,
+    secret: &[&str],
+) -> BTreeMap<String, String> {
+    let is_secret = |name: &str| {
+        SENSITIVE_HEADERS
+            .iter()
+            .chain(secret)
+            .any(|s| name.eq_ignore_ascii_case(s))
+    };
+
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_secret(name.as_str()) {
+                "<redacted>".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Replace the values of `SECRET_FIELDS` in a JSON object or form encoded
+/// body. Other bodies are returned unchanged.
+fn redact_body(body: &str) -> String {
+    if let Ok(mut value) = serde_json::from_str::<serde_json::Value>(body) {
+        if let Some(object) = value.as_object_mut() {
+            let mut redacted = false;
+            for field in SECRET_FIELDS {
+                if let Some(v) = object.get_mut(field) {
+                    *v = serde_json::Value::String("<redacted>".to_string());
+                    redacted = true;
+                }
+            }
+            if redacted {
+                return value.to_string();
+            }
+        }
+        return body.to_string();
+    }
+
+    let pairs: Vec<(String, String)> = url::form_urlencoded::parse(body.as_bytes())
+        .into_owned()
+        .collect();
+    if !pairs
+        .iter()
+        .any(|(k, _)| SECRET_FIELDS.contains(&k.as_str()))
+    {
+        return body.to_string();
+    }
+
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    for (k, v) in &pairs {
+        if SECRET_FIELDS.contains(&k.as_str()) {
+            serializer.append_pair(k, "<redacted>");
+        } else {
+            serializer.append_pair(k, v);
+        }
+    }
+    serializer.finish()
+}
+
+/// Generate a random (version 4) UUID string.
+///
+/// The randomness comes from std's per-process random hasher keys, which is
+/// plenty for request identifiers without pulling in a RNG dependency.
+pub fn new_uuid() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::SystemTime;
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+
+    let random = |salt: u64| {
+        let mut h = RandomState::new().build_hasher();
+        h.write_u128(nanos);
+        h.write_u64(salt);
+        h.finish()
+    };
+
+    let hi = random(0);
+    let lo = random(1);
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&hi.to_be_bytes());
+    bytes[8..].copy_from_slice(&lo.to_be_bytes());
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// A local HTTP server for tests. Answers `requests` requests, one per
+/// connection, with status 200 and the body `respond` returns for the raw
+/// request text. The handle returns the requests received.
+#[cfg(test)]
+pub(crate) fn serve<F>(
+    requests: usize,
+    respond: F,
+) -> (String, std::thread::JoinHandle<Vec<String>>)
+where
+    F: Fn(&str) -> String + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = std::thread::spawn(move || {
+        let mut received = Vec::new();
+        for stream in listener.incoming().take(requests) {
+            let mut stream = stream.unwrap();
+            let mut data = Vec::new();
+            let mut buf = [0; 4096];
+            let request = loop {
+                let n = stream.read(&mut buf).unwrap();
+                data.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&data).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .filter_map(|l| l.split_once(':'))
+                        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                        .map(|(_, v)| v.trim().parse().unwrap())
+                        .unwrap_or(0);
+                    if body.len() >= length || n == 0 {
+                        break text;
+                    }
+                }
+            };
+
+            let body = respond(&request);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            received.push(request);
+        }
+        received
+    });
+
+    (endpoint, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_uuid, normalize_endpoint, parse_header, redact_body, redact_headers};
+
+    #[test]
+    fn header_syntax() {
+        let (name, value) = parse_header("X-Tenant-ID:  acme ").unwrap();
+        assert_eq!(name.as_str(), "x-tenant-id");
+        assert_eq!(value, "acme");
+        assert_eq!(parse_header("X-Empty:").unwrap().1, "");
+
+        assert_eq!(
+            parse_header("X-Tenant-ID acme").unwrap_err(),
+            "invalid header 'X-Tenant-ID acme': expected 'Name: value'"
+        );
+        assert_eq!(
+            parse_header("Bad Name: x").unwrap_err(),
+            "invalid header name 'Bad Name'"
+        );
+        assert!(parse_header("X-Line: a\nb").is_err());
+    }
+
+    #[test]
+    fn endpoint_normalization() {
+        assert_eq!(
+            normalize_endpoint("hapctl.internal", "https").unwrap(),
+            "https://hapctl.internal"
+        );
+        assert_eq!(
+            normalize_endpoint("hapctl.internal:8080/", "http").unwrap(),
+            "http://hapctl.internal:8080"
+        );
+        assert_eq!(
+            normalize_endpoint("http://hapctl.internal", "https").unwrap(),
+            "http://hapctl.internal"
+        );
+        assert_eq!(
+            normalize_endpoint("https://hapctl.internal/api", "https").unwrap(),
+            "https://hapctl.internal/api"
+        );
+        assert_eq!(
+            normalize_endpoint("[2001:db8::1]:8443", "https").unwrap(),
+            "https://[2001:db8::1]:8443"
+        );
+        assert_eq!(
+            normalize_endpoint("http://[::1]/", "https").unwrap(),
+            "http://[::1]"
+        );
+        let err = normalize_endpoint("2001:db8::1", "https").unwrap_err();
+        assert!(err.to_string().contains("must be in brackets"));
+        assert!(normalize_endpoint("ftp://hapctl.internal", "https").is_err());
+        assert!(normalize_endpoint("https://", "https").is_err());
+        assert!(normalize_endpoint("bad host", "https").is_err());
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        assert_eq!(
+            redact_body("grant_type=apikey&apikey=s3cret"),
+            "grant_type=apikey&apikey=%3Credacted%3E"
+        );
+        assert_eq!(
+            redact_body(r#"{"access_token":"abc","expires_in":1200}"#),
+            r#"{"access_token":"<redacted>","expires_in":1200}"#
+        );
+        assert_eq!(
+            redact_body(r#"{"weight":50,"reason":"rebalance"}"#),
+            r#"{"weight":50,"reason":"rebalance"}"#
+        );
+        assert_eq!(redact_body("plain text"), "plain text");
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in [
+            ("Authorization", "Bearer abc"),
+            ("X-Tenant-Key", "t0ps3cret"),
+            ("Set-Cookie", "session=abc"),
+            ("X-Api-Key", "k3y"),
+            ("Content-Type", "application/json"),
+        ] {
+            headers.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let redacted = redact_headers(&headers, &["x-tenant-key"]);
+        for name in ["authorization", "x-tenant-key", "set-cookie", "x-api-key"] {
+            assert_eq!(redacted[name], "<redacted>");
+        }
+        assert_eq!(redacted["content-type"], "application/json");
+    }
+
+    #[test]
+    fn uuid_format() {
+        let id = new_uuid();
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|g| g.len()).collect::<Vec<_>>(),
+            vec![8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('4'));
+        assert!("89ab".contains(&groups[3][..1]));
+        assert_ne!(id, new_uuid());
+    }
+}
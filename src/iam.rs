@@ -16,14 +16,21 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
-use serde_json;
-use tracing::debug;
-use tracing_subscriber;
+use tracing::{debug, info, warn};
 use url::form_urlencoded;
 
+use crate::http;
+
+#[derive(Clone)]
 pub struct Client {
     api_key: String,
     token: Arc<Mutex<Option<Token>>>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    debug_net: bool,
+    http: Option<reqwest::blocking::Client>,
+    extra_params: Vec<(String, String)>,
+    max_response_size: usize,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -34,6 +41,65 @@ pub struct Token {
     pub expiry: Instant,
 }
 
+/// Why a token could not be obtained from IAM. The underlying reqwest or
+/// serde error, if any, is available through `source()`.
+#[derive(Debug)]
+pub enum IamError {
+    /// `IBMCLOUD_API_KEY` is not set.
+    MissingApiKey,
+    /// The request could not be built, sent or read.
+    Transport {
+        message: String,
+        source: reqwest::Error,
+    },
+    /// IAM answered with a non-success status.
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// The response was longer than the client's limit, see
+    /// `Client::with_max_response_size`.
+    ResponseTooLarge { limit: usize },
+    /// The response was not a token.
+    InvalidResponse(serde_json::Error),
+}
+
+impl std::fmt::Display for IamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IamError::MissingApiKey => write!(f, "'{}' not set or invalid", API_KEY_ENV),
+            IamError::Transport { message, .. } => write!(f, "get token failed: {}", message),
+            IamError::Status { status, body } => {
+                write!(f, "get token failed: IAM returned {}: {}", status, body)
+            }
+            IamError::ResponseTooLarge { limit } => write!(
+                f,
+                "get token failed: response is larger than the {} byte limit",
+                limit
+            ),
+            IamError::InvalidResponse(_) => write!(f, "get token failed: unexpected response"),
+        }
+    }
+}
+
+impl std::error::Error for IamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IamError::Transport { source, .. } => Some(source),
+            IamError::InvalidResponse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Seconds until `token` expires, for log events.
+pub(crate) fn expires_in_secs(token: &Token) -> u64 {
+    token.time_to_expiry().map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Environment variable holding the IAM API key.
+pub const API_KEY_ENV: &str = "IBMCLOUD_API_KEY";
+
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -45,16 +111,59 @@ impl From<TokenResponse> for Token {
         Token {
             access_token: tr.access_token,
             token_type: tr.token_type,
-            refresh_token: tr.refresh_token.unwrap_or_else(|| "".to_string()),
-            expiry: Instant::now() + Duration::from_secs(tr.expires_in.unwrap_or_else(|| 1200)),
+            refresh_token: tr.refresh_token.unwrap_or_default(),
+            expiry: Instant::now() + Duration::from_secs(tr.expires_in.unwrap_or(1200)),
         }
     }
 }
 
 impl Token {
+    /// A token expiring at `expiry`, e.g. a canned token for a test
+    /// `TokenProvider`.
+    pub fn new(access_token: &str, token_type: &str, refresh_token: &str, expiry: Instant) -> Self {
+        Token {
+            access_token: access_token.to_string(),
+            token_type: token_type.to_string(),
+            refresh_token: refresh_token.to_string(),
+            expiry,
+        }
+    }
+
     pub fn valid(&self) -> bool {
         Instant::now().checked_duration_since(self.expiry).is_none()
     }
+
+    /// How long until the token expires, or `None` if it already has.
+    pub fn time_to_expiry(&self) -> Option<Duration> {
+        if !self.valid() {
+            return None;
+        }
+        Some(self.expiry.saturating_duration_since(Instant::now()))
+    }
+
+    /// Human readable token metadata with the secrets elided.
+    pub fn redacted(&self) -> String {
+        let expires = match self.time_to_expiry() {
+            Some(d) => format!("in {}s", d.as_secs()),
+            None => "expired".to_string(),
+        };
+
+        format!(
+            "token_type: {}\naccess_token: {}\nrefresh_token: {}\nexpires: {}",
+            self.token_type,
+            redact(&self.access_token),
+            redact(&self.refresh_token),
+            expires
+        )
+    }
+}
+
+fn redact(secret: &str) -> String {
+    if secret.is_empty() {
+        "(none)".to_string()
+    } else {
+        format!("<redacted, {} chars>", secret.len())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -70,90 +179,230 @@ impl Client {
         Self {
             api_key: api_key.to_string(),
             token: Arc::new(Mutex::new(None)),
+            connect_timeout: http::DEFAULT_CONNECT_TIMEOUT,
+            timeout: http::DEFAULT_TIMEOUT,
+            debug_net: false,
+            http: None,
+            extra_params: Vec::new(),
+            max_response_size: http::DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Send token requests through `client` instead of the shared client.
+    pub fn with_http_client(mut self, client: reqwest::blocking::Client) -> Self {
+        self.http = Some(client);
+        self
+    }
+
+    /// Limit how long establishing a connection to IAM may take.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Limit how long a whole token request may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Build a client with the API key from `IBMCLOUD_API_KEY`.
+    pub fn from_env() -> Result<Self, IamError> {
+        match std::env::var(API_KEY_ENV) {
+            Ok(k) if !k.is_empty() => Ok(Self::new(&k)),
+            _ => Err(IamError::MissingApiKey),
         }
     }
 
-    pub fn token(&self) -> Result<Token, Box<dyn std::error::Error>> {
+    pub fn token(&self) -> Result<Token, IamError> {
         let mut token = self.token.lock().unwrap();
 
-        if let Some(t) = token.clone() {
-            if t.valid() {
-                return Ok(t);
+        let cause = match token.as_ref() {
+            Some(t) if t.valid() => {
+                debug!(
+                    source = "cache",
+                    expires_in_secs = expires_in_secs(t),
+                    "token from cache"
+                );
+                return Ok(t.clone());
             }
-        }
+            Some(_) => "expired",
+            None => "not cached",
+        };
+
+        let start = Instant::now();
+        let new = match self.request_token() {
+            Ok(t) => t,
+            Err(e) => {
+                warn!(
+                    source = "iam",
+                    cause,
+                    elapsed_ms = start.elapsed().as_millis() as u64,
+                    error = %e,
+                    "token request failed"
+                );
+                return Err(e);
+            }
+        };
+        info!(
+            source = "iam",
+            cause,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            expires_in_secs = expires_in_secs(&new),
+            "token refreshed"
+        );
+        *token = Some(new.clone());
+
+        Ok(new)
+    }
+
+    /// Log a timing breakdown of token requests at info level.
+    pub fn with_net_debug(mut self, enabled: bool) -> Self {
+        self.debug_net = enabled;
+        self
+    }
+
+    /// Add a form parameter to token requests, after the API key grant.
+    ///
+    /// IAM accepts, among others, `response_type` (`cloud_iam`, or
+    /// `cloud_iam delegated_refresh_token` for a delegated refresh token) and
+    /// `receiver_client_ids` (who may use a delegated refresh token). Without
+    /// extra parameters the request is the plain API key grant.
+    pub fn with_form_param(mut self, name: &str, value: &str) -> Self {
+        self.extra_params
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Fail token requests whose response is longer than `bytes`.
+    pub fn with_max_response_size(mut self, bytes: usize) -> Self {
+        self.max_response_size = bytes;
+        self
+    }
 
-        *token = Some(self.request_token());
+    /// Drop any cached token so the next `token()` call requests a new one.
+    pub fn invalidate(&self) {
+        *self.token.lock().unwrap() = None;
+    }
 
-        Ok(token.as_ref().unwrap().clone())
+    fn form_body(&self) -> String {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "urn:ibm:params:oauth:grant-type:apikey")
+            .append_pair("apikey", &self.api_key);
+        for (name, value) in &self.extra_params {
+            form.append_pair(name, value);
+        }
+        form.finish()
     }
 
-    fn request_token(&self) -> Token {
-        let encoded: String = form_urlencoded::Serializer::new(String::new())
-            .append_pair("grant_type", "urn:ibm:params:oauth:grant-type:apikey")
-            .append_pair("apikey", &self.api_key)
-            .finish();
+    fn request_token(&self) -> Result<Token, IamError> {
+        let encoded = self.form_body();
+        let transport = |source: reqwest::Error| IamError::Transport {
+            message: http::describe_error(&source, self.connect_timeout, self.timeout),
+            source,
+        };
 
-        let c = reqwest::blocking::Client::new();
+        let c =
+            http::client_for(&self.http, self.connect_timeout, self.timeout).map_err(transport)?;
 
-        let resp = c
+        let request = c
             .post("https://iam.cloud.ibm.com/identity/token")
             .header("Authorization", "Basic Yng6Yng=")
             .header("Accept", "application/json")
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(encoded)
-            .send()
-            .expect("Get token failed");
+            .build()
+            .map_err(transport)?;
+        let mut timer = self.debug_net.then(|| http::NetTimer::start(request.url()));
+        let mut transcript = http::Transcript::start(&request, &[]);
+        let fail = |transcript: Option<http::Transcript>, e: reqwest::Error| {
+            let err = transport(e);
+            if let Some(t) = transcript {
+                t.failed(&err.to_string());
+            }
+            err
+        };
+
+        let resp = match c.execute(request) {
+            Ok(r) => r,
+            Err(e) => return Err(fail(transcript, e)),
+        };
+        let status = resp.status();
+        if let Some(t) = timer.as_mut() {
+            t.headers_received();
+        }
+        if let Some(t) = transcript.as_mut() {
+            t.response(status, resp.headers());
+        }
 
-        let text = resp.text().expect("Getting body text failed");
-        let token_resp: TokenResponse = serde_json::from_str(&text).unwrap();
+        let text = match http::read_body(resp, self.max_response_size) {
+            Ok(t) => t,
+            Err(http::BodyError::Transport(e)) => return Err(fail(transcript, e)),
+            Err(e) => {
+                let err = match e {
+                    http::BodyError::Io(e) => IamError::InvalidResponse(serde_json::Error::io(e)),
+                    _ => IamError::ResponseTooLarge {
+                        limit: self.max_response_size,
+                    },
+                };
+                if let Some(t) = transcript {
+                    t.failed(&err.to_string());
+                }
+                return Err(err);
+            }
+        };
+        if let Some(t) = timer {
+            t.finish(status);
+        }
+        if let Some(t) = transcript {
+            t.finish(Some(&text));
+        }
+
+        if !status.is_success() {
+            return Err(IamError::Status { status, body: text });
+        }
+        let token_resp: TokenResponse =
+            serde_json::from_str(&text).map_err(IamError::InvalidResponse)?;
 
-        token_resp.into()
+        Ok(token_resp.into())
     }
 }
 
+/// Panics if `IBMCLOUD_API_KEY` is not set; use `Client::from_env` to handle
+/// that as an error.
 impl Default for Client {
     fn default() -> Self {
-        let env_key = "IBMCLOUD_API_KEY";
-        let api_key = match std::env::var(env_key) {
-            Ok(k) => k,
-            _ => {
-                panic!("'IBMCLOUD_API_KEY' not set or invalid");
-            }
-        };
-
-        Self::new(&api_key)
+        match Self::from_env() {
+            Ok(c) => c,
+            Err(e) => panic!("{}", e),
+        }
     }
 }
 
 pub fn main() {
     tracing_subscriber::fmt::init();
 
-    let iam = Client::default();
-
-    let token = iam.token().unwrap();
-    debug!("Token: {:?}", token);
+    let token = match Client::from_env().and_then(|iam| iam.token()) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    debug!("token:\n{}", token.redacted());
     println!("AccessToken: {}", token.access_token);
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Client, Token};
+    use super::{Client, IamError, Token};
 
     use std::sync::Arc;
     use std::thread;
     use std::time::{Duration, Instant};
 
     fn get_test_token() -> Token {
-        let access_token = String::from("");
-        let refresh_token = String::from("");
-        let token_type = String::from("test");
-
-        Token {
-            access_token,
-            refresh_token,
-            token_type,
-            expiry: Instant::now() + Duration::from_secs(1200),
-        }
+        Token::new("", "test", "", Instant::now() + Duration::from_secs(1200))
     }
 
     #[test]
@@ -166,9 +415,55 @@ mod tests {
         assert!(!token.valid());
     }
 
+    #[test]
+    fn token_time_to_expiry() {
+        let mut token = get_test_token();
+        token.expiry = Instant::now() + Duration::from_secs(60);
+        let remaining = token.time_to_expiry().unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(50));
+
+        token.expiry = Instant::now() - Duration::from_secs(10);
+        assert_eq!(token.time_to_expiry(), None);
+    }
+
+    #[test]
+    fn token_redacted() {
+        let mut token = get_test_token();
+        token.access_token = String::from("secret-access-token");
+        let shown = token.redacted();
+        assert!(!shown.contains("secret-access-token"));
+        assert!(shown.contains("<redacted, 19 chars>"));
+    }
+
+    #[test]
+    fn form_params() {
+        let iam = Client::new("key");
+        assert_eq!(
+            iam.form_body(),
+            "grant_type=urn%3Aibm%3Aparams%3Aoauth%3Agrant-type%3Aapikey&apikey=key"
+        );
+
+        let iam = iam.with_form_param("response_type", "cloud_iam delegated_refresh_token");
+        assert!(iam
+            .form_body()
+            .ends_with("&apikey=key&response_type=cloud_iam+delegated_refresh_token"));
+    }
+
+    #[test]
+    fn error_source() {
+        use std::error::Error;
+
+        let parse = serde_json::from_str::<serde_json::Value>("not json").unwrap_err();
+        let err = IamError::InvalidResponse(parse);
+        assert_eq!(err.to_string(), "get token failed: unexpected response");
+        assert!(err.source().unwrap().to_string().contains("expected"));
+        assert!(IamError::MissingApiKey.source().is_none());
+    }
+
     #[test]
     fn token_caching() {
-        let iam = Client::new("".into());
+        let iam = Client::new("");
         *iam.token.lock().unwrap() = Some(get_test_token());
 
         let token = iam.token().unwrap();
@@ -176,9 +471,18 @@ mod tests {
         assert_eq!(token, token2);
     }
 
+    #[test]
+    fn invalidate_clears_cache() {
+        let iam = Client::new("");
+        *iam.token.lock().unwrap() = Some(get_test_token());
+
+        iam.clone().invalidate();
+        assert!(iam.token.lock().unwrap().is_none());
+    }
+
     #[test]
     fn threadsafe_cache() {
-        let iam = Client::new("".into());
+        let iam = Client::new("");
         *iam.token.lock().unwrap() = Some(get_test_token());
 
         let c = Arc::new(iam);
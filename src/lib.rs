@@ -1,4 +1,19 @@
+pub mod batch;
 pub mod client;
+pub mod config;
+pub mod env;
+pub mod expr;
+pub mod history;
+pub mod http;
 pub mod iam;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod plan;
+pub mod reason;
+pub mod regions;
+pub mod repl;
+pub mod routing;
+pub mod token;
 
-pub use crate::client::Client;
+pub use crate::client::{Client, ClientError as Error, ServerRef};
+pub use crate::iam::IamError;
@@ -1,45 +1,404 @@
-use clap::{arg, command, Command};
-use tracing_subscriber;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
-use hapctl;
+use clap::{arg, command, Arg, ArgMatches, Command};
+use reqwest::header::{HeaderName, HeaderValue};
+use rustyline::error::ReadlineError;
 
-fn main() {
-    tracing_subscriber::fmt::init();
+use hapctl::batch;
+use hapctl::client;
+use hapctl::config::{self, Config};
+use hapctl::history::{Change, History};
+use hapctl::http;
+use hapctl::iam;
+use hapctl::plan;
+use hapctl::reason;
+use hapctl::regions;
+use hapctl::routing;
+use hapctl::token::{FileTokenProvider, TokenProvider};
+use tracing_subscriber::EnvFilter;
 
-    let matches = command!()
+fn cli() -> Command<'static> {
+    command!()
         .arg(arg!(-e --endpoint <ENDPOINT> "Override the endpoint URL the client tries to connect to. Default is to auto-detect").required(false))
+        .arg(
+            arg!(--"endpoint-scheme" <SCHEME> "Scheme to use for an endpoint given as a bare host [default: https]")
+                .required(false)
+                .possible_values(["http", "https"]),
+        )
+        .arg(arg!(-c --config <PATH> "Config file to read. Defaults to $HAPCTL_CONFIG or ~/.config/hapctl/config.toml").required(false))
+        .arg(
+            arg!(--"env-prefix" <PREFIX> "Read PREFIX_CONFIG, PREFIX_PROFILE, PREFIX_ENDPOINT, PREFIX_API_KEY and PREFIX_INSECURE_LOG_BODY instead of the usual environment variables")
+                .required(false)
+                .validator(|s| {
+                    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        Ok(())
+                    } else {
+                        Err("must be letters, digits and underscores".to_string())
+                    }
+                }),
+        )
+        .arg(arg!(-r --region <REGION> "Use the endpoint for this region instead of guessing it from the server name").required(false))
+        .arg(arg!(-p --profile <NAME> "Config file profile to use. Defaults to $HAPCTL_PROFILE").required(false))
+        .arg(
+            arg!(--"connect-timeout" <SECS> "Seconds to wait for a connection to be established [default: 10]")
+                .required(false)
+                .validator(|s| s.parse::<u64>()),
+        )
+        .arg(
+            arg!(--timeout <SECS> "Seconds to wait for a whole request to complete [default: 30]")
+                .required(false)
+                .validator(|s| s.parse::<u64>()),
+        )
+        .arg(
+            arg!(--"endpoint-ping-timeout" <SECS> "Seconds to wait for a ping or preflight health check [default: 5]")
+                .required(false)
+                .validator(|s| s.parse::<u64>()),
+        )
+        .arg(arg!(--"endpoint-file" <PATH> "Read 'regex = endpoint' rules mapping server names to endpoints, checked before the region heuristic").required(false))
+        .arg(
+            arg!(--"servername-delimiter" <CHAR> "Character between backend and server in server names [default: /]")
+                .required(false)
+                .validator(|s| s.parse::<char>()),
+        )
+        .arg(arg!(--"no-auto-endpoint" "Fail instead of guessing the endpoint from the server name when no endpoint or region is given"))
+        .arg(arg!(--"regions-file" <PATH> "Read the region to endpoint table from a JSON file instead of the built-in one").required(false))
+        .arg(arg!(--"insecure-log-body" "Log full response bodies at debug level (may expose sensitive data). Also enabled by HAPCTL_INSECURE_LOG_BODY=1"))
+        .arg(arg!(-v --verbose "Print the underlying causes of errors"))
+        .arg(arg!(-q --quiet "Do not print the summary line after batch operations"))
+        .arg(arg!(--transcript <PATH> "Append a JSON record of every HTTP request and response to this file, with credentials redacted").required(false))
+        .arg(arg!(--"debug-net" "Log a timing breakdown of every HTTP request at info level"))
+        .arg(arg!(--credential <NAME> "Authenticate with the API key of this [credentials.NAME] config entry instead of IBMCLOUD_API_KEY").required(false))
+        .arg(arg!(--"token-file" <PATH> "Read the bearer token from this file instead of requesting one from IAM. The file is re-read when it changes or the token expires").required(false))
+        .arg(arg!(--"force-reauth" "Discard any cached IAM token and authenticate again"))
+        .arg(
+            arg!(--"weight-max" <N> "Largest weight to allow, for balancers capped below HAProxy's 256. The server still rejects values above its real limit")
+                .required(false)
+                .validator(|s| s.parse::<u32>()),
+        )
+        .arg(arg!(--preflight "Ping every endpoint a batch uses and abort before changing anything if one fails").overrides_with("no-preflight"))
+        .arg(
+            arg!(--header <HEADER> "Add 'Name: value' to every API request (repeatable)")
+                .required(false)
+                .multiple_occurrences(true)
+                .validator(|s| http::parse_header(s).map(|_| ())),
+        )
+        .arg(arg!(--"allow-managed-headers" "Let --header replace headers hapctl sets itself, such as Authorization"))
+        .arg(arg!(--"failures-out" <PATH> "Write the entries of a batch that failed or were never attempted to this plan file, for re-running with apply").required(false))
+        .arg(arg!(--"no-preflight" "Skip the preflight check even if the config enables it").overrides_with("preflight"))
+        .arg(
+            arg!(--"batch-chunk-size" <N> "Change at most N servers of a batch at a time, see --chunk-delay")
+                .required(false)
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(0) => Err("must be at least 1".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }),
+        )
+        .arg(
+            arg!(--"chunk-delay" <SECS> "Seconds to wait between batch chunks [default: 0]")
+                .required(false)
+                .validator(|s| s.parse::<u64>()),
+        )
+        .arg(arg!(--"require-fresh-token" "Fail a batch up front if no token valid for its estimated duration can be obtained"))
+        .arg(arg!(--"correlation-id" <ID> "ID sent in the correlation header of every API request. Generated and printed for changes when not given").required(false))
+        .arg(arg!(--"idempotency-key" "Send an Idempotency-Key header with mutating requests"))
         .subcommand_required(true)
         .arg_required_else_help(true)
         .subcommand(
             Command::new("get-weight")
                 .about("Check current server weights")
-                .arg(arg!(<SERVERNAME>))
+                .arg(arg!(<SERVERNAME> "Server name, or '-' to read names from stdin"))
+                .arg(arg!(--raw "Print the raw HTTP status line, headers and body"))
                 .arg_required_else_help(true),
         )
         .subcommand(
             Command::new("set-weight")
                 .about("Set a weight for a server")
-                .arg(arg!(<SERVERNAME>))
+                .arg(arg!(<SERVERNAME> "Server name, or '-' to read names from stdin"))
                 .arg(
                     arg!(<WEIGHT>)
                         .validator(|s| s.parse::<u32>())
                 )
-                .arg(arg!(<REASON>))
+                .arg(arg!([REASON]).required_unless_present_any(["no-reason", "reason-from-git"]))
+                .arg(
+                    arg!(--"reason-from-git" "Use the subject and short hash of the current git commit as the reason")
+                        .conflicts_with_all(&["REASON", "no-reason"]),
+                )
+                .arg(
+                    arg!(--repeat <N> "Send the write N times with one idempotency key, then check the weight took. For servers that drop writes")
+                        .required(false)
+                        .conflicts_with("raw")
+                        .validator(|s| match s.parse::<u32>() {
+                            Ok(0) => Err("must be at least 1".to_string()),
+                            Ok(_) => Ok(()),
+                            Err(e) => Err(e.to_string()),
+                        }),
+                )
+                .arg(
+                    arg!(--"no-reason" "Send no reason at all, for servers that do not require one")
+                        .conflicts_with_all(&["REASON", "meta"]),
+                )
+                .arg(
+                    arg!(--meta <KEY_VALUE> "Attach key=value metadata to the reason (repeatable)")
+                        .required(false)
+                        .multiple_occurrences(true)
+                        .validator(|s| match s.split_once('=') {
+                            Some((k, _)) if !k.is_empty() => Ok(()),
+                            _ => Err("expected KEY=VALUE"),
+                        }),
+                )
+                .arg(arg!(-y --yes "Do not ask for confirmation"))
+                .arg(arg!(--force "Allow a change larger than the configured max_weight_step"))
+                .arg(arg!(--raw "Print the raw HTTP status line, headers and body"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("apply")
+                .about("Apply the weight changes listed in a plan file")
+                .arg(
+                    arg!(--"servers-from" <FILE> "JSON array of {backend, server, weight, reason[, meta]} objects, or [[servers]] tables in a .toml file")
+                        .required_unless_present("dir")
+                        .conflicts_with("dir"),
+                )
+                .arg(arg!(--dir <DIR> "Merge all *.json and *.toml plans in this directory, in file name order").required(false))
+                .arg(var_arg())
+                .arg(arg!(--force "Allow changes larger than the configured max_weight_step"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("ramp")
+                .about("Raise a server's weight step by step, e.g. for a canary")
+                .arg(arg!(<SERVERNAME>))
+                .arg(
+                    arg!(--steps <PERCENTS> "Comma separated percentages of the full weight to step through, e.g. 5,25,50,100")
+                        .validator(|s| {
+                            s.split(',').try_for_each(|p| match p.trim().parse::<u32>() {
+                                Ok(p) if p <= 100 => Ok(()),
+                                _ => Err(format!("'{}' is not a percentage", p)),
+                            })
+                        }),
+                )
+                .arg(
+                    arg!(--interval <SECS> "Seconds to wait between steps")
+                        .validator(|s| s.parse::<u64>()),
+                )
+                .arg(
+                    arg!(--"full-weight" <WEIGHT> "Weight at 100%")
+                        .validator(|s| s.parse::<u32>()),
+                )
+                .arg(arg!(-y --yes "Do not ask for confirmation"))
+                .arg(arg!(--reason <REASON> "Reason recorded with every step").required(false))
+                .arg(arg!(--check "Ping the endpoint before each step and stop if it is unhealthy"))
+                .arg(arg!(--force "Allow steps larger than the configured max_weight_step"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("Check a plan file for errors without applying it")
+                .arg(arg!(<FILE> "JSON array of {backend, server, weight, reason[, meta]} objects, or [[servers]] tables in a .toml file"))
+                .arg(var_arg())
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("ping")
+                .about("Check that the endpoint is reachable and accepts our credentials")
+                .arg(arg!([SERVERNAME] "Server name used to pick the endpoint when --endpoint is not given")),
+        )
+        .subcommand(
+            Command::new("capabilities")
+                .about("Show which features the endpoint supports")
+                .arg(arg!([SERVERNAME] "Server name used to pick the endpoint when --endpoint is not given")),
+        )
+        .subcommand(
+            Command::new("token")
+                .about("Fetch an IAM token and show its (redacted) metadata")
+                .arg(arg!(--force "Request a new token even if one is cached"))
+                .arg(
+                    arg!(--output <FORMAT> "Output format [default: text]")
+                        .required(false)
+                        .possible_values(["text", "json", "json-pretty"]),
+                )
+                .arg(arg!(--"show-token" "Include the access and refresh tokens in JSON output").requires("output")),
+        )
+        .subcommand(
+            Command::new("resolve-endpoint")
+                .about("Show which endpoint a server name resolves to, and why")
+                .arg(arg!(<SERVERNAME>))
+                .arg(arg!(--explain "Also show how the servername template parses the name"))
                 .arg_required_else_help(true),
         )
-        .get_matches();
+        .subcommand(Command::new("regions").about("List known regions and their endpoints"))
+        .subcommand(
+            Command::new("history")
+                .about("Show recent weight changes from the local history file")
+                .arg(arg!(--server <SERVERNAME> "Only show changes to this server").required(false))
+                .arg(
+                    arg!(-n --limit <N> "Number of changes to show [default: 20]")
+                        .required(false)
+                        .validator(|s| s.parse::<usize>()),
+                ),
+        )
+        .subcommand(
+            Command::new("repl")
+                .about("Run commands from an interactive prompt, reusing one token and connection pool")
+                .alias("shell"),
+        )
+}
 
-    let endpoint = matches.value_of("endpoint");
+fn main() {
+    let matches = cli().get_matches();
+
+    if let Some(path) = matches.value_of("regions-file") {
+        if let Err(e) = regions::load_override(path) {
+            eprintln!("failed loading regions file {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = matches.value_of("endpoint-file") {
+        if let Err(e) = routing::load_rules(path) {
+            eprintln!("failed loading endpoint file {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    let config_path = matches
+        .value_of("config")
+        .map(PathBuf::from)
+        .or_else(|| env_var(&matches, config::CONFIG_ENV, "CONFIG").map(PathBuf::from));
+    let profile = matches
+        .value_of("profile")
+        .map(String::from)
+        .or_else(|| env_var(&matches, config::PROFILE_ENV, "PROFILE"));
+    let config = match Config::load_path(config_path.as_deref())
+        .and_then(|c| c.with_named_profile(profile.as_deref()))
+    {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    init_tracing(&matches, &config);
 
+    if let Some(path) = matches.value_of("transcript") {
+        if let Err(e) = http::set_transcript(path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let delimiter = matches
+        .is_present("servername-delimiter")
+        .then(|| matches.value_of_t_or_exit("servername-delimiter"))
+        .or(config.servername_delimiter);
+    if let Some(d) = delimiter {
+        if let Err(e) = client::set_servername_delimiter(d) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = routing::set_backend_endpoints(config.backend_endpoints.clone()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(template) = &config.servername_template {
+        if let Err(e) = routing::NameTemplate::parse(template, config.env_endpoints.clone())
+            .and_then(routing::set_name_template)
+        {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let opts = match Options::new(&matches, &config) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // One connection pool for the IAM and API requests of this command.
+    match http::build_client(opts.connect_timeout, opts.timeout) {
+        Ok(c) => http::set_shared_client(c).expect("shared client set twice"),
+        Err(e) => {
+            eprintln!("failed to set up HTTP client: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let code = run(&matches, &opts, &config);
+    #[cfg(feature = "otel")]
+    hapctl::otel::shutdown();
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+/// Log to stderr as filtered by `RUST_LOG`. With the `otel` feature and an
+/// OTLP endpoint configured, request spans are also exported.
+fn init_tracing(matches: &ArgMatches, config: &Config) {
+    let mut filter = EnvFilter::from_default_env();
+    if matches.is_present("debug-net") {
+        filter = filter.add_directive("hapctl=info".parse().unwrap());
+    }
+
+    #[cfg(feature = "otel")]
+    {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let endpoint = std::env::var(hapctl::otel::ENDPOINT_ENV)
+            .ok()
+            .or_else(|| config.otlp_endpoint.clone());
+        if let Some(endpoint) = endpoint {
+            // The request spans are at info level and are only exported if
+            // the filter lets them through.
+            filter = filter.add_directive("hapctl::client=info".parse().unwrap());
+            let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
+            let result = hapctl::otel::layer(&endpoint).and_then(|layer| {
+                Ok(tracing::subscriber::set_global_default(
+                    subscriber.with(layer),
+                )?)
+            });
+            if let Err(e) = result {
+                eprintln!("failed to set up OTLP export to {}: {}", endpoint, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        eprintln!("warning: otlp_endpoint is set but hapctl was built without the otel feature");
+    }
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+/// Run the subcommand in `matches` and return the exit status.
+fn run(matches: &ArgMatches, opts: &Options, config: &Config) -> i32 {
     match matches.subcommand() {
         Some(("get-weight", sub_matches)) => {
             let name = sub_matches.value_of("SERVERNAME").unwrap();
-            let client = hapctl::Client::new(name, endpoint);
 
-            println!(
-                "{}",
-                client.get_weight(&name).unwrap_or_else(|c| c.to_string())
-            );
+            if sub_matches.is_present("raw") {
+                return for_each_server(name, opts, None, |client, name| {
+                    Ok(client.get_weight_raw(name)?.to_string())
+                });
+            } else {
+                return for_each_server(name, opts, None, |client, name| {
+                    Ok(client.get_weight(name)?)
+                });
+            }
         }
         Some(("set-weight", sub_matches)) => {
             let name = sub_matches.value_of("SERVERNAME").unwrap();
@@ -48,16 +407,1276 @@ fn main() {
                 .unwrap()
                 .parse::<u32>()
                 .unwrap();
-            let reason = sub_matches.value_of("REASON").unwrap();
-            let client = hapctl::Client::new(name, endpoint);
-
-            println!(
-                "{}",
-                client
-                    .set_weight(&name, weight, &reason)
-                    .unwrap_or_else(|c| c.to_string())
+            let meta: BTreeMap<String, String> = sub_matches
+                .values_of("meta")
+                .into_iter()
+                .flatten()
+                .filter_map(|kv| kv.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let text = if sub_matches.is_present("reason-from-git") {
+                match git_reason() {
+                    Ok(r) => Some(r),
+                    Err(e) => {
+                        eprintln!("{}, pass a REASON instead", e);
+                        return 1;
+                    }
+                }
+            } else {
+                sub_matches.value_of("REASON").map(String::from)
+            };
+            if let Err(e) = check_reason(text.as_deref(), config) {
+                eprintln!("{}", e);
+                return 1;
+            }
+            let failed_entry = |name: &str| {
+                let server: client::ServerRef = name.parse().ok()?;
+                Some(plan::PlanEntry {
+                    backend: server.backend,
+                    server: server.server,
+                    weight,
+                    reason: text.clone(),
+                    meta: meta.clone(),
+                })
+            };
+            let reason = text
+                .as_deref()
+                .map(|text| make_reason(text, meta.clone(), config));
+
+            if !confirm_weight(weight, config, sub_matches.is_present("yes")) {
+                return 1;
+            }
+
+            let max_step = config
+                .max_weight_step
+                .filter(|_| !sub_matches.is_present("force"));
+
+            print_correlation_id(matches, opts);
+            if sub_matches.is_present("raw") {
+                return for_each_server(name, opts, Some(&failed_entry), |client, name| {
+                    check_step(client, name, weight, max_step)?;
+                    recorded(opts, client, name, weight, reason.as_deref(), || {
+                        Ok(client
+                            .set_weight_raw_opt(name, weight, reason.as_deref())?
+                            .to_string())
+                    })
+                });
+            } else if sub_matches.is_present("repeat") {
+                let repeat: u32 = sub_matches.value_of_t_or_exit("repeat");
+                return for_each_server(name, opts, Some(&failed_entry), |client, name| {
+                    check_step(client, name, weight, max_step)?;
+                    recorded(opts, client, name, weight, reason.as_deref(), || {
+                        Ok(client
+                            .set_weight_repeated(name, weight, reason.as_deref(), repeat)?
+                            .to_string())
+                    })
+                });
+            } else {
+                return for_each_server(name, opts, Some(&failed_entry), |client, name| {
+                    check_step(client, name, weight, max_step)?;
+                    recorded(opts, client, name, weight, reason.as_deref(), || {
+                        Ok(client
+                            .set_weight_opt(name, weight, reason.as_deref())?
+                            .to_string())
+                    })
+                });
+            }
+        }
+        Some(("apply", sub_matches)) => {
+            let vars = plan_vars(sub_matches);
+            let entries = match sub_matches.value_of("dir") {
+                Some(dir) => plan::load_dir(dir, &vars, opts.weight_max),
+                None => plan::load(
+                    sub_matches.value_of("servers-from").unwrap(),
+                    &vars,
+                    opts.weight_max,
+                ),
+            };
+            let entries = match entries {
+                Ok(e) => e,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            };
+
+            let errors = plan_errors(&entries, opts, config);
+            if !errors.is_empty() {
+                for e in &errors {
+                    eprintln!("{}", e);
+                }
+                eprintln!("{} invalid entries, nothing applied", errors.len());
+                return 1;
+            }
+
+            let max_step = config
+                .max_weight_step
+                .filter(|_| !sub_matches.is_present("force"));
+
+            print_correlation_id(matches, opts);
+            return run_batch(
+                &entries,
+                opts,
+                |entry| entry.server_name(),
+                Some(&|entry: &plan::PlanEntry| Some(entry.clone())),
+                |client, entry| {
+                    let name = entry.server_name();
+                    check_step(client, &name, entry.weight, max_step)?;
+                    let reason = entry
+                        .reason
+                        .as_deref()
+                        .map(|text| make_reason(text, entry.meta.clone(), config));
+                    recorded(opts, client, &name, entry.weight, reason.as_deref(), || {
+                        Ok(client
+                            .set_weight_opt(&name, entry.weight, reason.as_deref())?
+                            .to_string())
+                    })
+                },
             );
         }
+        Some(("ramp", sub_matches)) => {
+            let steps: Vec<u32> = sub_matches
+                .value_of("steps")
+                .unwrap()
+                .split(',')
+                .map(|p| p.trim().parse().unwrap())
+                .collect();
+            let full: u32 = sub_matches.value_of_t_or_exit("full-weight");
+            let top = steps.iter().max().map_or(0, |p| ramp_weight(*p, full));
+            if !confirm_weight(top, config, sub_matches.is_present("yes")) {
+                return 1;
+            }
+
+            print_correlation_id(matches, opts);
+            return ramp(
+                sub_matches.value_of("SERVERNAME").unwrap(),
+                &steps,
+                full,
+                Duration::from_secs(sub_matches.value_of_t_or_exit("interval")),
+                sub_matches.value_of("reason"),
+                sub_matches.is_present("check"),
+                config
+                    .max_weight_step
+                    .filter(|_| !sub_matches.is_present("force")),
+                opts,
+                config,
+            );
+        }
+        Some(("validate", sub_matches)) => {
+            let path = sub_matches.value_of("FILE").unwrap();
+            let entries = match plan::load(path, &plan_vars(sub_matches), opts.weight_max) {
+                Ok(e) => e,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            };
+
+            let errors = plan_errors(&entries, opts, config);
+            for e in &errors {
+                println!("{}", e);
+            }
+            if !errors.is_empty() {
+                eprintln!("{} of {} entries invalid", errors.len(), entries.len());
+                return 1;
+            }
+            println!("{} entries ok", entries.len());
+        }
+        Some(("ping", sub_matches)) => {
+            let name = sub_matches.value_of("SERVERNAME");
+            match opts
+                .token_provider()
+                .and_then(|tokens| opts.untargeted_client(name, &tokens))
+                .and_then(|c| {
+                    let ping = c.ping()?;
+                    let health = if ping.healthy() {
+                        c.health().ok()
+                    } else {
+                        None
+                    };
+                    Ok((ping, health))
+                }) {
+                Ok((ping, health)) => {
+                    println!("{}", ping);
+                    if let Some(client::HealthStatus::Known(h)) = health {
+                        println!("health: {}", h);
+                    }
+                    if !ping.healthy() {
+                        return 1;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("unreachable: {}", opts.describe(&*e));
+                    return 1;
+                }
+            }
+        }
+        Some(("capabilities", sub_matches)) => {
+            let name = sub_matches.value_of("SERVERNAME");
+            match opts
+                .token_provider()
+                .and_then(|tokens| opts.untargeted_client(name, &tokens))
+                .and_then(|c| Ok(c.probe_endpoint()?))
+            {
+                Ok(capabilities) => println!("{}", capabilities),
+                Err(e) => {
+                    eprintln!("{}", opts.describe(&*e));
+                    return 1;
+                }
+            }
+        }
+        Some(("token", sub_matches)) => {
+            let token = opts.token_provider().and_then(|tokens| {
+                if sub_matches.is_present("force") {
+                    tokens.invalidate();
+                }
+                tokens.token()
+            });
+            let show_token = sub_matches.is_present("show-token");
+            match (token, sub_matches.value_of("output")) {
+                (Ok(token), Some("json")) => {
+                    println!("{}", hapctl::token::details(&token, show_token))
+                }
+                (Ok(token), Some("json-pretty")) => {
+                    println!("{:#}", hapctl::token::details(&token, show_token))
+                }
+                (Ok(token), _) => println!("{}", token.redacted()),
+                (Err(e), _) => {
+                    eprintln!("{}", opts.describe(&*e));
+                    return 1;
+                }
+            }
+        }
+        Some(("resolve-endpoint", sub_matches)) => {
+            let name = sub_matches.value_of("SERVERNAME").unwrap();
+            if sub_matches.is_present("explain") {
+                explain_name(name);
+            }
+            match opts.endpoint_for(name) {
+                Ok((endpoint, source)) => println!("{} (from {})", endpoint, source),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            }
+        }
+        Some(("repl", _)) => return repl(opts, config),
+        Some(("history", sub_matches)) => {
+            let history = match &opts.history {
+                Some(h) => h,
+                None => {
+                    eprintln!("no history_file configured");
+                    return 1;
+                }
+            };
+            let server = sub_matches
+                .value_of("server")
+                .map(|s| s.parse::<client::ServerRef>().map(|r| r.to_string()));
+            let server = match server.transpose() {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            };
+            let limit = if sub_matches.is_present("limit") {
+                sub_matches.value_of_t_or_exit("limit")
+            } else {
+                20
+            };
+
+            match history.read(server.as_deref(), limit) {
+                Ok(changes) => {
+                    for change in changes {
+                        println!("{}", change);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            }
+        }
+        Some(("regions", _)) => {
+            let table = regions::table();
+            for region in regions::known_regions() {
+                println!("{}\t{}", region, table.regions[&region].endpoint);
+            }
+            println!("(default)\t{}", table.default.endpoint);
+        }
         _ => unreachable!("No subcommand found"),
     }
+
+    0
+}
+
+/// Read commands at a prompt and run them like the subcommands of the same
+/// name, sharing one token provider and connection pool. Global options come
+/// from the `repl` invocation and cannot be changed per command.
+fn repl(opts: &Options, config: &Config) -> i32 {
+    let mut editor = match rustyline::DefaultEditor::new() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("cannot start the repl: {}", e);
+            return 1;
+        }
+    };
+    let globals: Vec<String> = cli()
+        .get_arguments()
+        .map(|a| a.get_id().to_string())
+        .collect();
+
+    loop {
+        let line = match editor.readline("hapctl> ") {
+            Ok(l) => l,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        if matches!(line, "exit" | "quit") {
+            return 0;
+        }
+
+        let words = match hapctl::repl::split_words(line) {
+            Ok(w) => w,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        let matches =
+            match cli().try_get_matches_from(std::iter::once("hapctl".to_string()).chain(words)) {
+                Ok(m) => m,
+                Err(e) => {
+                    let _ = e.print();
+                    continue;
+                }
+            };
+
+        if let Some(global) = globals.iter().find(|g| matches.is_present(g)) {
+            eprintln!("--{} must be given when starting the repl", global);
+        } else if matches.subcommand_name() == Some("repl") {
+            eprintln!("already in the repl");
+        } else {
+            let code = run(&matches, opts, config);
+            if code != 0 {
+                eprintln!("exit status {}", code);
+            }
+        }
+    }
+}
+
+/// Show a generated correlation ID before a change so it can be recorded,
+/// e.g. in the change ticket.
+fn print_correlation_id(matches: &ArgMatches, opts: &Options) {
+    if !matches.is_present("correlation-id") {
+        eprintln!("correlation id: {}", opts.correlation.1);
+    }
+}
+
+/// Enforce `min_reason_length`, if configured; a missing reason counts as empty.
+fn check_reason(text: Option<&str>, config: &Config) -> Result<(), String> {
+    match config.min_reason_length {
+        Some(min) => reason::check_length(text.unwrap_or(""), min),
+        None => Ok(()),
+    }
+}
+
+/// `plan::validate` plus the configured reason policy, one message per
+/// invalid entry.
+fn plan_errors(entries: &[plan::PlanEntry], opts: &Options, config: &Config) -> Vec<String> {
+    let mut errors = plan::validate(entries, opts.weight_max);
+    for (i, entry) in entries.iter().enumerate() {
+        if let Err(e) = check_reason(entry.reason.as_deref(), config) {
+            errors.push(format!(
+                "entry {} ({}/{}): {}",
+                i, entry.backend, entry.server, e
+            ));
+        }
+    }
+    errors
+}
+
+/// Build the reason sent with a weight change, adding operator context when
+/// the config asks for it.
+fn make_reason(text: &str, mut meta: BTreeMap<String, String>, config: &Config) -> String {
+    if !config.reason_context.unwrap_or(false) {
+        return client::structured_reason(text, &meta);
+    }
+
+    reason::add_context(text, &mut meta, reason::operator_context());
+    reason::fit(text, &meta, reason::MAX_REASON_LEN)
+}
+
+/// The repeatable `--var KEY=VALUE` option for weight expressions in plans.
+fn var_arg() -> Arg<'static> {
+    arg!(--var <KEY_VALUE> "Set a variable for weight expressions in the plan (repeatable)")
+        .required(false)
+        .multiple_occurrences(true)
+        .validator(|s| plan::parse_var(s).map(|_| ()))
+}
+
+fn plan_vars(matches: &ArgMatches) -> plan::Vars {
+    matches
+        .values_of("var")
+        .into_iter()
+        .flatten()
+        .filter_map(|kv| plan::parse_var(kv).ok())
+        .collect()
+}
+
+/// The `--header` headers. Headers hapctl manages itself are refused unless
+/// `--allow-managed-headers` is given.
+fn extra_headers(
+    matches: &ArgMatches,
+    config: &Config,
+) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    let correlation = config
+        .correlation_header
+        .as_deref()
+        .unwrap_or(DEFAULT_CORRELATION_HEADER);
+    let managed = [
+        "Authorization",
+        "Content-Type",
+        "Idempotency-Key",
+        correlation,
+    ];
+
+    let mut headers = Vec::new();
+    for header in matches.values_of("header").into_iter().flatten() {
+        let (name, value) = http::parse_header(header)?;
+        if let Some(m) = managed
+            .iter()
+            .find(|m| m.eq_ignore_ascii_case(name.as_str()))
+        {
+            if !matches.is_present("allow-managed-headers") {
+                return Err(format!(
+                    "header '{}' is set by hapctl, pass --allow-managed-headers to replace it",
+                    m
+                ));
+            }
+        }
+        headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+/// Print how the servername template, if any, splits `name` into fields.
+fn explain_name(name: &str) {
+    let template = match routing::name_template() {
+        Some(t) => t,
+        None => {
+            println!("no servername_template configured");
+            return;
+        }
+    };
+
+    match template.fields(name) {
+        Some(fields) => {
+            println!("template '{}' matches:", template.as_str());
+            for (field, value) in fields {
+                println!("  {} = {}", field, value);
+            }
+        }
+        None => println!(
+            "template '{}' does not match, using the usual rules",
+            template.as_str()
+        ),
+    }
+}
+
+/// Run `set`, which moves `name` to `weight`, and append the change to the
+/// history file if one is configured, see `Change::track`. Failing to write
+/// the history only warns.
+fn recorded<F>(
+    opts: &Options,
+    client: &hapctl::Client,
+    name: &str,
+    weight: u32,
+    reason: Option<&str>,
+    set: F,
+) -> Result<String, Box<dyn std::error::Error>>
+where
+    F: FnOnce() -> Result<String, Box<dyn std::error::Error>>,
+{
+    let history = match &opts.history {
+        Some(h) => h,
+        None => return set(),
+    };
+
+    let (result, change) = Change::track(client, name, weight, reason, set)?;
+    if let Err(e) = history.record(&change) {
+        eprintln!("warning: {}", e);
+    }
+    Ok(result)
+}
+
+/// Refuse to move `name` to `weight` if that changes its current weight by
+/// more than `max_step`. No limit means no check, and no extra request.
+fn check_step(
+    client: &hapctl::Client,
+    name: &str,
+    weight: u32,
+    max_step: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match max_step {
+        Some(max_step) => Ok(client.check_weight_step(name, weight, max_step)?),
+        None => Ok(()),
+    }
+}
+
+/// Environment variable overriding the endpoint, after `--endpoint` and
+/// `--region` but before the config file.
+const ENDPOINT_ENV: &str = "HAPCTL_ENDPOINT";
+
+/// Environment variable that enables `--insecure-log-body` when set to `1`.
+const LOG_BODY_ENV: &str = "HAPCTL_INSECURE_LOG_BODY";
+
+/// The name of an environment variable hapctl reads: `<PREFIX>_<suffix>`
+/// with `--env-prefix`, otherwise `default`.
+fn env_name(matches: &ArgMatches, default: &str, suffix: &str) -> String {
+    match matches.value_of("env-prefix") {
+        Some(prefix) => format!("{}_{}", prefix, suffix),
+        None => default.to_string(),
+    }
+}
+
+/// The value of the environment variable `env_name` picks, if set and not
+/// empty.
+fn env_var(matches: &ArgMatches, default: &str, suffix: &str) -> Option<String> {
+    std::env::var(env_name(matches, default, suffix))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+/// The subject and short hash of the commit checked out in the current
+/// directory, e.g. `Drain web3 for kernel update (1a2b3c4)`.
+fn git_reason() -> Result<String, String> {
+    let output = std::process::Command::new("git")
+        .args(["log", "-1", "--pretty=format:%s (%h)"])
+        .output()
+        .map_err(|e| format!("cannot run git: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("git log failed: {}", stderr.trim()));
+    }
+
+    let reason = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if reason.is_empty() {
+        return Err("git log returned no commit".to_string());
+    }
+    Ok(reason)
+}
+
+/// Ask before setting `weight` if it is above the configured `warn_above`.
+/// Returns whether to go ahead.
+fn confirm_weight(weight: u32, config: &Config, assume_yes: bool) -> bool {
+    match config.warn_above {
+        Some(limit) if weight > limit => {
+            eprintln!(
+                "warning: weight {} is above the configured warn_above limit of {}",
+                weight, limit
+            );
+            confirm("Set it anyway?", assume_yes)
+        }
+        _ => true,
+    }
+}
+
+/// Ask the operator to confirm on the terminal. `assume_yes` (from `--yes`)
+/// skips the prompt; without a terminal to ask on, the answer is no.
+fn confirm(prompt: &str, assume_yes: bool) -> bool {
+    if assume_yes {
+        return true;
+    }
+
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        eprintln!("refusing to continue without confirmation, pass --yes to proceed");
+        return false;
+    }
+
+    eprint!("{} [y/N] ", prompt);
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    if stdin.lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+const DEFAULT_CORRELATION_HEADER: &str = "X-Correlation-ID";
+
+/// Connection settings shared by every client a command creates.
+struct Options {
+    /// Explicitly chosen endpoint and where it was chosen, if any.
+    endpoint: Option<(String, &'static str)>,
+    /// The region that chose `endpoint`, if it was chosen by region.
+    region: Option<String>,
+    /// Regions seen in server names that disagree with `region`, so each
+    /// is warned about once.
+    warned_regions: Mutex<BTreeSet<String>>,
+    /// Fall back to guessing the endpoint from the server name.
+    auto_endpoint: bool,
+    connect_timeout: Duration,
+    timeout: Duration,
+    ping_timeout: Duration,
+    idempotency_keys: bool,
+    log_body: bool,
+    force_reauth: bool,
+    /// Token provider shared by every command run, e.g. from the repl.
+    tokens: OnceLock<Arc<dyn TokenProvider>>,
+    token_file: Option<PathBuf>,
+    /// API key from a configured credential, if one was selected, or from
+    /// the environment.
+    api_key: Option<String>,
+    /// Environment variable the API key is read from.
+    api_key_env: String,
+    api_base_path: String,
+    debug_net: bool,
+    weight_max: u32,
+    quiet: bool,
+    verbose: bool,
+    preflight: bool,
+    chunk_size: Option<usize>,
+    chunk_delay: Duration,
+    /// Local log of weight changes, if configured.
+    history: Option<History>,
+    /// Extra headers for every API request, from `--header`.
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// Plan file to write the failed entries of a batch to.
+    failures_out: Option<PathBuf>,
+    require_fresh_token: bool,
+    /// Correlation header name and ID.
+    correlation: (String, String),
+}
+
+impl Options {
+    /// Command line flags take precedence over the config file.
+    fn new(matches: &ArgMatches, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let secs = |name, configured: Option<u64>, default: Duration| {
+            let flag = matches
+                .is_present(name)
+                .then(|| matches.value_of_t_or_exit(name));
+            flag.or(configured)
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+
+        let scheme = matches
+            .value_of("endpoint-scheme")
+            .or(config.endpoint_scheme.as_deref())
+            .unwrap_or(http::DEFAULT_SCHEME);
+        let normalize = |e: &str| http::normalize_endpoint(e, scheme);
+
+        let mut region = None;
+        let endpoint = if let Some(e) = matches.value_of("endpoint") {
+            Some((normalize(e)?, "--endpoint"))
+        } else if let Some(r) = matches.value_of("region") {
+            region = Some(r.to_string());
+            Some((regions::endpoint_for(r)?, "--region"))
+        } else if let Some(e) = env_var(matches, ENDPOINT_ENV, "ENDPOINT") {
+            Some((normalize(&e)?, "environment"))
+        } else if let Some(e) = &config.endpoint {
+            Some((normalize(e)?, "config endpoint"))
+        } else if let Some(r) = &config.region {
+            region = Some(r.clone());
+            Some((regions::endpoint_for(r)?, "config region"))
+        } else {
+            None
+        };
+
+        Ok(Options {
+            endpoint,
+            region,
+            warned_regions: Mutex::new(BTreeSet::new()),
+            auto_endpoint: !matches.is_present("no-auto-endpoint")
+                && config.auto_endpoint.unwrap_or(true),
+            connect_timeout: secs(
+                "connect-timeout",
+                config.connect_timeout,
+                client::DEFAULT_CONNECT_TIMEOUT,
+            ),
+            timeout: secs("timeout", config.timeout, client::DEFAULT_TIMEOUT),
+            ping_timeout: secs(
+                "endpoint-ping-timeout",
+                config.ping_timeout,
+                client::DEFAULT_PING_TIMEOUT,
+            ),
+            idempotency_keys: matches.is_present("idempotency-key"),
+            log_body: matches.is_present("insecure-log-body")
+                || env_var(matches, LOG_BODY_ENV, "INSECURE_LOG_BODY").is_some_and(|v| v == "1"),
+            force_reauth: matches.is_present("force-reauth"),
+            tokens: OnceLock::new(),
+            token_file: matches.value_of("token-file").map(PathBuf::from),
+            api_key: match config.api_key(matches.value_of("credential"))? {
+                Some(key) => Some(key),
+                None => env_var(matches, iam::API_KEY_ENV, "API_KEY"),
+            },
+            api_key_env: env_name(matches, iam::API_KEY_ENV, "API_KEY"),
+            api_base_path: config
+                .api_base_path
+                .clone()
+                .unwrap_or_else(|| client::DEFAULT_API_BASE_PATH.to_string()),
+            debug_net: matches.is_present("debug-net"),
+            weight_max: matches
+                .is_present("weight-max")
+                .then(|| matches.value_of_t_or_exit("weight-max"))
+                .or(config.weight_max)
+                .unwrap_or(client::MAX_WEIGHT),
+            quiet: matches.is_present("quiet"),
+            verbose: matches.is_present("verbose"),
+            preflight: if matches.is_present("preflight") {
+                true
+            } else if matches.is_present("no-preflight") {
+                false
+            } else {
+                config.preflight.unwrap_or(false)
+            },
+            chunk_size: matches
+                .is_present("batch-chunk-size")
+                .then(|| matches.value_of_t_or_exit("batch-chunk-size")),
+            chunk_delay: secs("chunk-delay", None, Duration::ZERO),
+            failures_out: matches.value_of("failures-out").map(PathBuf::from),
+            headers: extra_headers(matches, config)?,
+            history: config.history_path().map(|path| {
+                History::new(path).with_max_bytes(
+                    config
+                        .history_max_bytes
+                        .unwrap_or(hapctl::history::DEFAULT_MAX_BYTES),
+                )
+            }),
+            require_fresh_token: matches.is_present("require-fresh-token"),
+            correlation: (
+                config
+                    .correlation_header
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_CORRELATION_HEADER.to_string()),
+                matches
+                    .value_of("correlation-id")
+                    .map(String::from)
+                    .unwrap_or_else(http::new_uuid),
+            ),
+        })
+    }
+
+    /// Resolve the endpoint for `name` when none was chosen explicitly. With
+    /// the heuristic disabled only endpoint rules and backend endpoints are
+    /// consulted.
+    fn resolve(&self, name: &str) -> Result<routing::Resolution, Box<dyn std::error::Error>> {
+        if self.auto_endpoint {
+            return Ok(routing::resolve(name));
+        }
+
+        routing::resolve_configured(name).ok_or_else(|| {
+            format!(
+                "no endpoint for '{}': automatic endpoint detection is disabled, pass --endpoint or --region",
+                name
+            )
+            .into()
+        })
+    }
+
+    fn client(
+        &self,
+        name: &str,
+        tokens: &Arc<dyn TokenProvider>,
+    ) -> Result<hapctl::Client, Box<dyn std::error::Error>> {
+        let (endpoint, _) = self.endpoint_for(name)?;
+        Ok(self.configure(hapctl::Client::for_endpoint(&endpoint), tokens))
+    }
+
+    /// The endpoint for `name` and what chose it. A backend endpoint wins
+    /// over `--region` or a config region, which only stand in for the
+    /// default endpoint.
+    fn endpoint_for(&self, name: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
+        if self.region.is_some() {
+            if let Some(resolution) = routing::resolve_backend(name) {
+                return Ok((resolution.endpoint, resolution.source.to_string()));
+            }
+        }
+
+        match &self.endpoint {
+            Some((e, source)) => {
+                self.check_region(name, source);
+                Ok((e.clone(), source.to_string()))
+            }
+            None => {
+                let resolution = self.resolve(name)?;
+                Ok((resolution.endpoint, resolution.source.to_string()))
+            }
+        }
+    }
+
+    /// A client for commands that do not target a server. `name`, if given,
+    /// only picks the endpoint; without it the explicit endpoint or region is
+    /// used, then the default endpoint.
+    fn untargeted_client(
+        &self,
+        name: Option<&str>,
+        tokens: &Arc<dyn TokenProvider>,
+    ) -> Result<hapctl::Client, Box<dyn std::error::Error>> {
+        if let Some(name) = name {
+            return self.client(name, tokens);
+        }
+
+        let endpoint = match &self.endpoint {
+            Some((e, _)) => e.clone(),
+            None if self.auto_endpoint => regions::table().default.endpoint.clone(),
+            None => {
+                return Err(
+                    "automatic endpoint detection is disabled, pass --endpoint, --region or a server name"
+                        .into(),
+                )
+            }
+        };
+        Ok(self.configure(hapctl::Client::for_endpoint(&endpoint), tokens))
+    }
+
+    fn configure(&self, client: hapctl::Client, tokens: &Arc<dyn TokenProvider>) -> hapctl::Client {
+        let client = client
+            .with_token_provider(tokens.clone())
+            .with_connect_timeout(self.connect_timeout)
+            .with_timeout(self.timeout)
+            .with_ping_timeout(self.ping_timeout)
+            .with_idempotency_keys(self.idempotency_keys)
+            .with_body_logging(self.log_body)
+            .with_net_debug(self.debug_net)
+            .with_weight_max(self.weight_max)
+            .with_api_base_path(&self.api_base_path)
+            .with_correlation_id(&self.correlation.0, &self.correlation.1);
+        self.headers.iter().fold(client, |c, (name, value)| {
+            c.with_header(name.clone(), value.clone())
+        })
+    }
+
+    /// Warn when `name` names a different region than the one given
+    /// explicitly, which is usually a copy-paste mistake.
+    fn check_region(&self, name: &str, source: &str) {
+        let region = match &self.region {
+            Some(r) => r,
+            None => return,
+        };
+        if let Some(guess) = regions::table().conflicting_region(name, region) {
+            if self
+                .warned_regions
+                .lock()
+                .unwrap()
+                .insert(guess.to_string())
+            {
+                eprintln!(
+                    "warning: '{}' looks like region '{}' but {} selects region '{}'",
+                    name, guess, source, region
+                );
+            }
+        }
+    }
+
+    fn token_provider(&self) -> Result<Arc<dyn TokenProvider>, Box<dyn std::error::Error>> {
+        if let Some(t) = self.tokens.get() {
+            return Ok(t.clone());
+        }
+
+        let tokens: Arc<dyn TokenProvider> = match &self.token_file {
+            Some(path) => Arc::new(FileTokenProvider::new(path)),
+            None => Arc::new(
+                match &self.api_key {
+                    Some(key) => iam::Client::new(key),
+                    None => return Err(format!("'{}' not set or invalid", self.api_key_env).into()),
+                }
+                .with_connect_timeout(self.connect_timeout)
+                .with_timeout(self.timeout)
+                .with_net_debug(self.debug_net),
+            ),
+        };
+        if self.force_reauth {
+            tokens.invalidate();
+        }
+        Ok(self.tokens.get_or_init(|| tokens).clone())
+    }
+
+    /// The error message, followed by its causes with `--verbose`.
+    fn describe(&self, e: &dyn std::error::Error) -> String {
+        let mut message = e.to_string();
+        if self.verbose {
+            let mut source = e.source();
+            while let Some(s) = source {
+                message.push_str(&format!("\n  caused by: {}", s));
+                source = s.source();
+            }
+        }
+        message
+    }
+}
+
+/// How a batch turns an item that failed or was skipped into a plan entry for
+/// `--failures-out`, for commands whose items can be re-applied.
+type FailedEntry<'a, T> = Option<&'a dyn Fn(&T) -> Option<plan::PlanEntry>>;
+
+/// Run `op` against `name`, or against every server name read from stdin when
+/// `name` is `-`. All servers share one token provider so the token is fetched once.
+/// Returns the exit status.
+fn for_each_server<F>(name: &str, opts: &Options, failed_entry: FailedEntry<'_, str>, op: F) -> i32
+where
+    F: Fn(&hapctl::Client, &str) -> Result<String, Box<dyn std::error::Error>>,
+{
+    if name != batch::STDIN_MARKER {
+        let result = opts
+            .token_provider()
+            .and_then(|tokens| opts.client(name, &tokens))
+            .and_then(|client| op(&client, name));
+        match result {
+            Ok(body) => println!("{}", body),
+            Err(e) => {
+                eprintln!("{}", opts.describe(&*e));
+                return 1;
+            }
+        }
+        return 0;
+    }
+
+    let names = match batch::read_server_names(std::io::stdin().lock()) {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("failed reading server names from stdin: {}", e);
+            return 1;
+        }
+    };
+
+    let failed_entry = failed_entry.map(|f| move |name: &String| f(name));
+    run_batch(
+        &names,
+        opts,
+        |name| name.clone(),
+        failed_entry
+            .as_ref()
+            .map(|f| f as &dyn Fn(&String) -> Option<plan::PlanEntry>),
+        |client, name| op(client, name),
+    )
+}
+
+/// Run `op` for every item, each against a client for the server named by
+/// `name`, printing per-item results and a summary. All clients share one IAM
+/// client so the token is fetched once.
+///
+/// With `--batch-chunk-size` the items are processed in chunks, pausing for
+/// `--chunk-delay` between them and, with `--preflight`, re-checking endpoint
+/// health before each chunk. Returns the exit status: non-zero if any item
+/// failed or the batch stopped early, `EXIT_INTERRUPTED` if Ctrl-C stopped it.
+fn run_batch<T, N, F>(
+    items: &[T],
+    opts: &Options,
+    name: N,
+    failed_entry: FailedEntry<'_, T>,
+    op: F,
+) -> i32
+where
+    N: Fn(&T) -> String,
+    F: Fn(&hapctl::Client, &T) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let tokens = match opts.token_provider() {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", opts.describe(&*e));
+            return 1;
+        }
+    };
+    if opts.preflight {
+        if let Err(e) = preflight(items.iter().map(&name), opts, &tokens) {
+            eprintln!("preflight failed, nothing applied: {}", e);
+            return 1;
+        }
+    }
+    let chunk_size = opts.chunk_size.unwrap_or(items.len()).max(1);
+    let chunks: Vec<&[T]> = items.chunks(chunk_size).collect();
+
+    let estimate = ESTIMATED_REQUEST_TIME * items.len() as u32
+        + opts.chunk_delay * chunks.len().saturating_sub(1) as u32;
+    if let Err(e) = ensure_fresh_token(&*tokens, estimate) {
+        if opts.require_fresh_token {
+            eprintln!("token check failed, nothing applied: {}", e);
+            return 1;
+        }
+        if !opts.quiet {
+            eprintln!("warning: {}", e);
+        }
+    }
+
+    let interrupted = interrupt_flag();
+    let mut summary = batch::Summary::default();
+    let mut stopped = false;
+    let mut failed = Vec::new();
+
+    'chunks: for (c, chunk) in chunks.iter().enumerate() {
+        if c > 0 {
+            pause(opts.chunk_delay, &interrupted);
+            if opts.preflight && !interrupted.load(Ordering::SeqCst) {
+                if let Err(e) = preflight(chunk.iter().map(&name), opts, &tokens) {
+                    eprintln!("health check before chunk {} failed: {}", c + 1, e);
+                    stopped = true;
+                }
+            }
+        }
+
+        for item in chunk.iter() {
+            if stopped || interrupted.load(Ordering::SeqCst) {
+                // Items run in order, so the rest were never attempted.
+                summary.skip(items.len() - summary.total);
+                break 'chunks;
+            }
+
+            let name = name(item);
+            let result = opts
+                .client(&name, &tokens)
+                .and_then(|client| op(&client, item));
+            match &result {
+                Ok(body) => println!("{}: {}", name, body),
+                Err(e) => {
+                    println!("{}: error: {}", name, opts.describe(&**e));
+                    failed.push(summary.total);
+                }
+            }
+            summary.record(&result);
+        }
+
+        if chunks.len() > 1 {
+            eprintln!("chunk {}/{} done: {}", c + 1, chunks.len(), summary);
+        }
+    }
+
+    if let (Some(path), Some(entry)) = (&opts.failures_out, failed_entry) {
+        let (failed, skipped) = batch::leftovers(items, &failed, summary.total - summary.skipped);
+        let mut entries: Vec<plan::PlanEntry> = failed.into_iter().filter_map(entry).collect();
+        let failed = entries.len();
+        entries.extend(skipped.iter().filter_map(entry));
+        match plan::save(path, &entries) {
+            Ok(()) if !entries.is_empty() => eprintln!(
+                "{} failed and {} skipped entries written to {}",
+                failed,
+                entries.len() - failed,
+                path.display()
+            ),
+            Ok(()) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    if interrupted.load(Ordering::SeqCst) && summary.skipped > 0 {
+        eprintln!("interrupted, {} servers not attempted", summary.skipped);
+        println!("{}", summary);
+        return EXIT_INTERRUPTED;
+    }
+    if !opts.quiet || summary.skipped > 0 {
+        println!("{}", summary);
+    }
+    if summary.failed > 0 || summary.skipped > 0 {
+        return 1;
+    }
+    0
+}
+
+/// Step `name` through `steps`, percentages of `full`, waiting `interval`
+/// between steps. With `check` the endpoint is pinged before every step after
+/// the first and the ramp stops at the current weight if it is unhealthy.
+/// A step that moves the weight by more than `max_step` stops the ramp.
+/// Returns the exit status.
+#[allow(clippy::too_many_arguments)]
+fn ramp(
+    name: &str,
+    steps: &[u32],
+    full: u32,
+    interval: Duration,
+    reason: Option<&str>,
+    check: bool,
+    max_step: Option<u32>,
+    opts: &Options,
+    config: &Config,
+) -> i32 {
+    if let Err(e) = check_reason(reason, config) {
+        eprintln!("{}", e);
+        return 1;
+    }
+
+    let client = match opts
+        .token_provider()
+        .and_then(|tokens| opts.client(name, &tokens))
+    {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", opts.describe(&*e));
+            return 1;
+        }
+    };
+    let interrupted = interrupt_flag();
+
+    for (i, percent) in steps.iter().enumerate() {
+        if i > 0 {
+            pause(interval, &interrupted);
+            if interrupted.load(Ordering::SeqCst) {
+                eprintln!("interrupted, ramp stopped at {}%", steps[i - 1]);
+                return EXIT_INTERRUPTED;
+            }
+            if check {
+                let failure = match client.ping() {
+                    Ok(ping) if ping.healthy() => None,
+                    Ok(ping) => Some(ping.to_string()),
+                    Err(e) => Some(format!("unreachable: {}", opts.describe(&e))),
+                };
+                if let Some(failure) = failure {
+                    eprintln!(
+                        "health check failed, ramp stopped at {}%: {}",
+                        steps[i - 1],
+                        failure
+                    );
+                    return 1;
+                }
+            }
+        }
+
+        let weight = ramp_weight(*percent, full);
+        let step = format!("ramp step {}/{}: {}%", i + 1, steps.len(), percent);
+        let text = match reason {
+            Some(r) => format!("{} ({})", r, step),
+            None => step,
+        };
+        let reason = make_reason(&text, BTreeMap::new(), config);
+
+        let result = check_step(&client, name, weight, max_step).and_then(|_| {
+            recorded(opts, &client, name, weight, Some(&reason), || {
+                Ok(client.set_weight(name, weight, &reason)?.to_string())
+            })
+        });
+        match result {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("ramp step {} failed: {}", i + 1, opts.describe(&*e));
+                return 1;
+            }
+        }
+    }
+
+    0
+}
+
+/// `percent` of `full`, rounded.
+fn ramp_weight(percent: u32, full: u32) -> u32 {
+    ((u64::from(percent) * u64::from(full) + 50) / 100) as u32
+}
+
+/// Sleep for `delay`, waking early if the batch is interrupted.
+fn pause(delay: Duration, interrupted: &AtomicBool) {
+    let step = Duration::from_millis(100);
+    let start = std::time::Instant::now();
+
+    while !interrupted.load(Ordering::SeqCst) {
+        let elapsed = start.elapsed();
+        if elapsed >= delay {
+            break;
+        }
+        std::thread::sleep(step.min(delay - elapsed));
+    }
+}
+
+/// Rough time one request of a batch takes, for estimating how long a
+/// batch will need its token.
+const ESTIMATED_REQUEST_TIME: Duration = Duration::from_secs(1);
+
+/// Make sure the token will last `needed`, fetching a new one up front if the
+/// current one expires sooner.
+fn ensure_fresh_token(tokens: &dyn TokenProvider, needed: Duration) -> Result<(), String> {
+    let remaining = |tokens: &dyn TokenProvider| {
+        tokens
+            .token()
+            .map(|t| t.time_to_expiry().unwrap_or_default())
+            .map_err(|e| format!("getting token: {}", e))
+    };
+
+    if remaining(tokens)? >= needed {
+        return Ok(());
+    }
+    tokens.invalidate();
+    let left = remaining(tokens)?;
+    if left < needed {
+        return Err(format!(
+            "token expires in {}s but the batch may take {}s",
+            left.as_secs(),
+            needed.as_secs()
+        ));
+    }
+    Ok(())
+}
+
+/// Exit status after a batch is stopped with Ctrl-C, as a shell reports a
+/// process killed by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Catch Ctrl-C so a batch can finish the request in flight and stop before
+/// the next one. A second Ctrl-C exits immediately.
+///
+/// The handler is installed once and the flag cleared for every batch, so in
+/// the repl an interrupt only stops the batch it arrived during.
+fn interrupt_flag() -> Arc<AtomicBool> {
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+    let flag = FLAG.get_or_init(|| {
+        let flag = Arc::new(AtomicBool::new(false));
+        let handler_flag = flag.clone();
+
+        let result = ctrlc::set_handler(move || {
+            if handler_flag.swap(true, Ordering::SeqCst) {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            eprintln!(
+                "interrupt received, stopping after the current request (Ctrl-C again to abort)"
+            );
+        });
+        if let Err(e) = result {
+            eprintln!("warning: cannot handle Ctrl-C: {}", e);
+        }
+
+        flag
+    });
+    flag.store(false, Ordering::SeqCst);
+    flag.clone()
+}
+
+/// Ping each distinct endpoint the named servers resolve to, failing with
+/// the first one that is unreachable or rejects our token.
+fn preflight<I>(names: I, opts: &Options, tokens: &Arc<dyn TokenProvider>) -> Result<(), String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut checked = BTreeSet::new();
+
+    for name in names {
+        // Names that fail to resolve are reported per item by the batch.
+        let client = match opts.client(&name, tokens) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        if !checked.insert(client.endpoint().to_string()) {
+            continue;
+        }
+
+        let failure = match client.ping() {
+            Ok(ping) if ping.healthy() => continue,
+            Ok(ping) => match client.health() {
+                Ok(client::HealthStatus::Known(h)) => format!("{}; health: {}", ping, h),
+                _ => ping.to_string(),
+            },
+            Err(e) => format!("{}: unreachable: {}", client.endpoint(), e),
+        };
+        return Err(failure);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,68 @@
+use opentelemetry::propagation::Injector;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Environment variable naming the OTLP/HTTP traces endpoint, as used by
+/// other OpenTelemetry SDKs.
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+
+/// A tracing layer that exports spans to the OTLP/HTTP collector at
+/// `endpoint`, the full traces URL such as
+/// `http://localhost:4318/v1/traces`. Also installs the W3C trace context
+/// propagator `inject` uses.
+pub fn layer<S>(endpoint: &str) -> Result<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "hapctl")])),
+        )
+        .install_simple()?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Export the spans still queued. Call once before exiting.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Add the `traceparent` header of the current span to `headers`, so the
+/// server can join its spans to ours. Does nothing outside an exported span.
+pub fn inject(headers: &mut HeaderMap) {
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
@@ -0,0 +1,460 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::{servername_delimiter, MAX_WEIGHT};
+use crate::expr::Expr;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// One weight change in a plan file.
+///
+/// Plan files are JSON arrays of these objects:
+///
+/// ```json
+/// [{"backend": "web", "server": "web1", "weight": 50, "reason": "rebalance"}]
+/// ```
+///
+/// In a plan file `weight` may also be an expression over variables given
+/// with `--var`, e.g. `"weight": "base * canary_fraction"`. Its value is
+/// rounded and clamped to the allowed weight range.
+///
+/// An entry may also carry `meta`, key/value metadata sent with the reason
+/// as with `set-weight --meta`. In JSON plans `"reason": null` sends no
+/// reason at all, as with `set-weight --no-reason`.
+///
+/// Plan files ending in `.toml` hold the same objects as `[[servers]]`
+/// tables instead. In either format `${VAR}` in strings is expanded from
+/// the environment, see `env::expand`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PlanEntry {
+    pub backend: String,
+    pub server: String,
+    pub weight: u32,
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+impl PlanEntry {
+    /// The `backend/server` name used by the client.
+    pub fn server_name(&self) -> String {
+        format!("{}{}{}", self.backend, servername_delimiter(), self.server)
+    }
+
+    fn validate(&self, weight_max: u32) -> std::result::Result<(), String> {
+        let delimiter = servername_delimiter();
+        for (field, value) in [("backend", &self.backend), ("server", &self.server)] {
+            if value.is_empty() {
+                return Err(format!("{} is empty", field));
+            }
+            if value.contains(delimiter) {
+                return Err(format!(
+                    "{} '{}' must not contain '{}'",
+                    field, value, delimiter
+                ));
+            }
+        }
+
+        if self.weight > weight_max {
+            return Err(format!(
+                "weight {} is out of range 0-{}",
+                self.weight, weight_max
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Values for the variables in weight expressions.
+pub type Vars = BTreeMap<String, f64>;
+
+/// Parse a `KEY=VALUE` variable for weight expressions. The value must be a
+/// finite number.
+pub fn parse_var(kv: &str) -> std::result::Result<(String, f64), String> {
+    match kv.split_once('=') {
+        Some((k, v)) if !k.is_empty() => match v.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok((k.to_string(), value)),
+            _ => Err(format!("value of '{}' is not a finite number", k)),
+        },
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum WeightSpec {
+    Fixed(u32),
+    Expr(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TemplateEntry {
+    backend: String,
+    server: String,
+    weight: WeightSpec,
+    #[serde(deserialize_with = "required")]
+    reason: Option<String>,
+    #[serde(default)]
+    meta: BTreeMap<String, String>,
+}
+
+/// Deserialize an `Option` field that must be present, even if only as
+/// `null`, so a forgotten reason is still an error.
+fn required<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TomlPlan {
+    #[serde(default)]
+    servers: Vec<TemplateEntry>,
+}
+
+pub fn parse_json(data: &str) -> Result<Vec<PlanEntry>> {
+    render(data, &Vars::new(), MAX_WEIGHT)
+}
+
+/// Parse a plan file, evaluating weight expressions with `vars` and
+/// clamping their results to `0..=weight_max`. Every expression is parsed
+/// before any is evaluated, so syntax errors are reported regardless of the
+/// variables given.
+pub fn render(data: &str, vars: &Vars, weight_max: u32) -> Result<Vec<PlanEntry>> {
+    let mut value: serde_json::Value = serde_json::from_str(data)?;
+    crate::env::expand_json(&mut value)?;
+    render_entries(serde_json::from_value(value)?, vars, weight_max)
+}
+
+/// Like `render`, for a TOML plan of `[[servers]]` tables.
+pub fn render_toml(data: &str, vars: &Vars, weight_max: u32) -> Result<Vec<PlanEntry>> {
+    let mut value: toml::Value = toml::from_str(data)?;
+    crate::env::expand_toml(&mut value)?;
+    let plan: TomlPlan = value.try_into()?;
+    render_entries(plan.servers, vars, weight_max)
+}
+
+fn render_entries(raw: Vec<TemplateEntry>, vars: &Vars, weight_max: u32) -> Result<Vec<PlanEntry>> {
+    let context =
+        |i: usize, s: &str, e: Box<dyn std::error::Error>| -> Box<dyn std::error::Error> {
+            format!("entry {}: weight '{}': {}", i, s, e).into()
+        };
+
+    let mut parsed = Vec::with_capacity(raw.len());
+    for (i, entry) in raw.into_iter().enumerate() {
+        let expr = match &entry.weight {
+            WeightSpec::Fixed(_) => None,
+            WeightSpec::Expr(s) => Some(Expr::parse(s).map_err(|e| context(i, s, e))?),
+        };
+        parsed.push((entry, expr));
+    }
+
+    parsed
+        .into_iter()
+        .enumerate()
+        .map(|(i, (entry, expr))| {
+            let weight = match (&entry.weight, expr) {
+                (WeightSpec::Expr(s), Some(expr)) => {
+                    let value = expr.eval(vars).map_err(|e| context(i, s, e))?;
+                    if !value.is_finite() {
+                        return Err(context(i, s, format!("evaluates to {}", value).into()));
+                    }
+                    value.round().clamp(0.0, f64::from(weight_max)) as u32
+                }
+                (WeightSpec::Fixed(w), _) => *w,
+                (WeightSpec::Expr(_), None) => unreachable!("expression weights are parsed above"),
+            };
+            Ok(PlanEntry {
+                backend: entry.backend,
+                server: entry.server,
+                weight,
+                reason: entry.reason,
+                meta: entry.meta,
+            })
+        })
+        .collect()
+}
+
+pub fn load<P: AsRef<Path>>(path: P, vars: &Vars, weight_max: u32) -> Result<Vec<PlanEntry>> {
+    let path = path.as_ref();
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("reading plan {}: {}", path.display(), e))?;
+
+    let entries = if path.extension().is_some_and(|e| e == "toml") {
+        render_toml(&data, vars, weight_max)
+    } else {
+        render(&data, vars, weight_max)
+    };
+    entries.map_err(|e| format!("parsing plan {}: {}", path.display(), e).into())
+}
+
+/// Write `entries` to `path` as a JSON plan file that `load` reads back.
+pub fn save<P: AsRef<Path>>(path: P, entries: &[PlanEntry]) -> Result<()> {
+    let path = path.as_ref();
+    let data = serde_json::to_string_pretty(entries)? + "\n";
+    std::fs::write(path, data).map_err(|e| format!("writing plan {}: {}", path.display(), e).into())
+}
+
+/// Load and merge every `*.json` and `*.toml` plan in `dir`, in file name
+/// order. A server listed in more than one file is an error naming both
+/// files, so each server has a single owner.
+pub fn load_dir<P: AsRef<Path>>(dir: P, vars: &Vars, weight_max: u32) -> Result<Vec<PlanEntry>> {
+    let dir = dir.as_ref();
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .map_err(|e| format!("reading plan directory {}: {}", dir.display(), e))?
+    {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "json" || e == "toml") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut merged = Vec::new();
+    let mut owners: HashMap<String, PathBuf> = HashMap::new();
+    let mut conflicts = Vec::new();
+    for path in paths {
+        for entry in load(&path, vars, weight_max)? {
+            match owners.get(&entry.server_name()) {
+                Some(owner) if *owner != path => conflicts.push(format!(
+                    "{} is in both {} and {}",
+                    entry.server_name(),
+                    owner.display(),
+                    path.display()
+                )),
+                Some(_) => merged.push(entry),
+                None => {
+                    owners.insert(entry.server_name(), path.clone());
+                    merged.push(entry);
+                }
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(conflicts.join("\n").into());
+    }
+    Ok(merged)
+}
+
+/// Check every entry against `weight_max` and the server name rules, and
+/// reject servers listed more than once, returning one message per invalid
+/// entry naming its index, so all problems can be reported before anything
+/// is applied.
+pub fn validate(entries: &[PlanEntry], weight_max: u32) -> Vec<String> {
+    let mut first_seen = HashMap::new();
+
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| {
+            let result = entry.validate(weight_max).and_then(|_| {
+                match first_seen.entry(entry.server_name()) {
+                    Entry::Occupied(first) => Err(format!("duplicate of entry {}", first.get())),
+                    Entry::Vacant(v) => {
+                        v.insert(i);
+                        Ok(())
+                    }
+                }
+            });
+
+            result
+                .err()
+                .map(|e| format!("entry {} ({}/{}): {}", i, entry.backend, entry.server, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, load_dir, parse_json, parse_var, render, render_toml, save, validate, Vars};
+
+    #[test]
+    fn custom_weight_max() {
+        let entries =
+            parse_json(r#"[{"backend": "web", "server": "web1", "weight": 150, "reason": "r"}]"#)
+                .unwrap();
+
+        assert!(validate(&entries, 256).is_empty());
+        assert_eq!(
+            validate(&entries, 100),
+            vec!["entry 0 (web/web1): weight 150 is out of range 0-100"]
+        );
+    }
+
+    #[test]
+    fn parse_and_validate() {
+        let entries = parse_json(
+            r#"[
+                {"backend": "web", "server": "web1", "weight": 50, "reason": "ok"},
+                {"backend": "web", "server": "web2", "weight": 300, "reason": "too big"},
+                {"backend": "", "server": "web3", "weight": 1, "reason": "no backend"},
+                {"backend": "web", "server": "a/b", "weight": 1, "reason": "bad name"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(entries[0].server_name(), "web/web1");
+        assert_eq!(
+            validate(&entries, 256),
+            vec![
+                "entry 1 (web/web2): weight 300 is out of range 0-256",
+                "entry 2 (/web3): backend is empty",
+                "entry 3 (web/a/b): server 'a/b' must not contain '/'",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_entries() {
+        let entries = parse_json(
+            r#"[
+                {"backend": "web", "server": "web1", "weight": 50, "reason": "a"},
+                {"backend": "web", "server": "web2", "weight": 50, "reason": "b"},
+                {"backend": "web", "server": "web1", "weight": 0, "reason": "c"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            validate(&entries, 256),
+            vec!["entry 2 (web/web1): duplicate of entry 0"]
+        );
+    }
+
+    #[test]
+    fn weight_expressions() {
+        let plan = r#"[
+            {"backend": "web", "server": "web1", "weight": "base * frac", "reason": "canary"},
+            {"backend": "web", "server": "web2", "weight": "base - base * frac", "reason": "rest"},
+            {"backend": "web", "server": "web3", "weight": "base * 10", "reason": "clamped"}
+        ]"#;
+        let vars = Vars::from([("base".to_string(), 100.0), ("frac".to_string(), 0.333)]);
+
+        let weights: Vec<u32> = render(plan, &vars, 256)
+            .unwrap()
+            .iter()
+            .map(|e| e.weight)
+            .collect();
+        assert_eq!(weights, vec![33, 67, 256]);
+
+        let err = render(plan, &Vars::new(), 256).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "entry 0: weight 'base * frac': unknown variable 'base'"
+        );
+
+        let bad = r#"[{"backend": "web", "server": "web1", "weight": "base *", "reason": "r"}]"#;
+        assert!(render(bad, &vars, 256).is_err());
+
+        let inf = Vars::from([
+            ("base".to_string(), f64::INFINITY),
+            ("frac".to_string(), 0.5),
+        ]);
+        let err = render(plan, &inf, 256).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "entry 0: weight 'base * frac': evaluates to inf"
+        );
+        let nan =
+            r#"[{"backend": "web", "server": "web1", "weight": "base - base", "reason": "r"}]"#;
+        assert!(render(nan, &inf, 256).is_err());
+    }
+
+    #[test]
+    fn var_parsing() {
+        assert_eq!(parse_var("base=100"), Ok(("base".to_string(), 100.0)));
+        assert_eq!(parse_var("frac=0.25"), Ok(("frac".to_string(), 0.25)));
+        for bad in ["base=NaN", "base=inf", "base=-inf", "base=x", "=1", "base"] {
+            assert!(parse_var(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn toml_plan() {
+        let entries = render_toml(
+            r#"
+            [[servers]]
+            backend = "web"
+            server = "web1"
+            weight = "base / 2"
+            reason = "half"
+            "#,
+            &Vars::from([("base".to_string(), 100.0)]),
+            256,
+        )
+        .unwrap();
+        assert_eq!(entries[0].server_name(), "web/web1");
+        assert_eq!(entries[0].weight, 50);
+    }
+
+    #[test]
+    fn plan_directory() {
+        let dir = std::env::temp_dir().join(format!("hapctl-plans-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("10-web.json"),
+            r#"[{"backend": "web", "server": "web1", "weight": 10, "reason": "web team"}]"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("20-api.toml"),
+            "[[servers]]\nbackend = \"api\"\nserver = \"api1\"\nweight = 20\nreason = \"api team\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("README"), "not a plan").unwrap();
+
+        let names: Vec<String> = load_dir(&dir, &Vars::new(), 256)
+            .unwrap()
+            .iter()
+            .map(|e| e.server_name())
+            .collect();
+        assert_eq!(names, vec!["web/web1", "api/api1"]);
+
+        std::fs::write(
+            dir.join("30-web.json"),
+            r#"[{"backend": "web", "server": "web1", "weight": 0, "reason": "other team"}]"#,
+        )
+        .unwrap();
+        let err = load_dir(&dir, &Vars::new(), 256).unwrap_err().to_string();
+        assert!(err.starts_with("web/web1 is in both "));
+        assert!(err.contains("10-web.json") && err.contains("30-web.json"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let entries = parse_json(
+            r#"[
+                {"backend": "web", "server": "web1", "weight": 5, "reason": "retry"},
+                {"backend": "web", "server": "web2", "weight": 5, "reason": null,
+                 "meta": {"ticket": "OPS-1234"}}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(entries[1].reason, None);
+        assert_eq!(entries[1].meta["ticket"], "OPS-1234");
+        let path = std::env::temp_dir().join(format!("hapctl-failed-{}.json", std::process::id()));
+
+        // A saved entry without a reason reloads without one, not with "".
+        save(&path, &entries).unwrap();
+        assert_eq!(load(&path, &Vars::new(), 256).unwrap(), entries);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_field() {
+        assert!(parse_json(r#"[{"backend": "web", "server": "web1"}]"#).is_err());
+        let no_reason = r#"[{"backend": "web", "server": "web1", "weight": 5}]"#;
+        assert!(parse_json(no_reason).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::client::structured_reason;
+
+/// Longest reason string sent to the server once context is added. Longer
+/// reasons have their free-form text shortened to fit.
+pub const MAX_REASON_LEN: usize = 1024;
+
+/// Who is making a change, from where and when: the `user`, `host` and `at`
+/// (UTC, RFC 3339) metadata keys. Values that cannot be determined are left
+/// out.
+pub fn operator_context() -> BTreeMap<String, String> {
+    let mut context = BTreeMap::new();
+
+    if let Some(user) = ["USER", "USERNAME"]
+        .iter()
+        .find_map(|k| std::env::var(k).ok().filter(|v| !v.is_empty()))
+    {
+        context.insert("user".to_string(), user);
+    }
+    if let Some(host) = hostname() {
+        context.insert("host".to_string(), host);
+    }
+    context.insert("at".to_string(), utc_timestamp(SystemTime::now()));
+
+    context
+}
+
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Add `context` to `meta`, skipping keys the operator already set and
+/// values the reason text already mentions.
+pub fn add_context(
+    reason: &str,
+    meta: &mut BTreeMap<String, String>,
+    context: BTreeMap<String, String>,
+) {
+    for (key, value) in context {
+        if !meta.contains_key(&key) && !reason.contains(&value) {
+            meta.insert(key, value);
+        }
+    }
+}
+
+/// The structured reason for `reason` and `meta`, shortening the reason text
+/// with a trailing `...` so the result is at most `max_len` bytes. Metadata is
+/// never cut, so when it alone is too long the text is reduced to `...`.
+pub fn fit(reason: &str, meta: &BTreeMap<String, String>, max_len: usize) -> String {
+    let full = structured_reason(reason, meta);
+    if full.len() <= max_len {
+        return full;
+    }
+
+    let mut chars: Vec<char> = reason.chars().collect();
+    while !chars.is_empty() {
+        chars.pop();
+        let short: String = chars.iter().collect::<String>() + "...";
+        let candidate = structured_reason(&short, meta);
+        if candidate.len() <= max_len {
+            return candidate;
+        }
+    }
+
+    structured_reason("...", meta)
+}
+
+/// Check that `reason` has at least `min_len` characters, not counting
+/// surrounding whitespace.
+pub fn check_length(reason: &str, min_len: usize) -> Result<(), String> {
+    let reason = reason.trim();
+    let len = reason.chars().count();
+    if len >= min_len {
+        return Ok(());
+    }
+
+    if len == 0 {
+        Err(format!(
+            "a reason of at least {} characters is required",
+            min_len
+        ))
+    } else {
+        Err(format!(
+            "reason '{}' is too short: {} characters, at least {} required",
+            reason, len, min_len
+        ))
+    }
+}
+
+/// Format `time` as `YYYY-MM-DDTHH:MM:SSZ`.
+pub(crate) fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_context, check_length, fit, utc_timestamp, MAX_REASON_LEN};
+
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn map(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn timestamp_format() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(1_709_210_096)),
+            "2024-02-29T12:34:56Z"
+        );
+    }
+
+    #[test]
+    fn context_does_not_override() {
+        let mut meta = map(&[("user", "alice")]);
+        add_context(
+            "rebalance for web7",
+            &mut meta,
+            map(&[("user", "bob"), ("host", "web7"), ("at", "now")]),
+        );
+        assert_eq!(meta, map(&[("user", "alice"), ("at", "now")]));
+    }
+
+    #[test]
+    fn context_added_to_reason() {
+        let mut meta = map(&[("ticket", "OPS-1234")]);
+        add_context(
+            "drain web3",
+            &mut meta,
+            map(&[("user", "bob"), ("host", "ops1")]),
+        );
+        assert_eq!(
+            fit("drain web3", &meta, MAX_REASON_LEN),
+            r#"{"reason":"drain web3","meta":{"host":"ops1","ticket":"OPS-1234","user":"bob"}}"#
+        );
+    }
+
+    #[test]
+    fn reason_length() {
+        assert!(check_length("drain web3 for OPS-1234", 10).is_ok());
+        assert!(check_length("", 0).is_ok());
+        assert_eq!(
+            check_length("   ", 10).unwrap_err(),
+            "a reason of at least 10 characters is required"
+        );
+        assert_eq!(
+            check_length(" fix ", 10).unwrap_err(),
+            "reason 'fix' is too short: 3 characters, at least 10 required"
+        );
+    }
+
+    #[test]
+    fn fit_truncates_reason() {
+        let meta = map(&[("user", "alice")]);
+        let reason = "x".repeat(100);
+
+        let full = fit(&reason, &meta, 1024);
+        assert!(full.contains(&reason));
+
+        let short = fit(&reason, &meta, 60);
+        assert!(short.len() <= 60);
+        assert!(short.contains("xxx...\""));
+        assert!(short.contains("alice"));
+    }
+}
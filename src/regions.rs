@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const EMBEDDED_REGIONS: &str = include_str!("regions.json");
+
+static TABLE: OnceLock<RegionTable> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegionEndpoints {
+    pub endpoint: String,
+}
+
+/// Region to endpoint mapping, compiled in from `regions.json`.
+///
+/// A server name containing a region key (e.g. `eu-de`) is routed to that
+/// region's endpoints; anything else goes to `default`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegionTable {
+    pub default: RegionEndpoints,
+    pub regions: HashMap<String, RegionEndpoints>,
+}
+
+impl RegionTable {
+    fn parse(data: &str) -> Result<Self> {
+        Ok(serde_json::from_str(data)?)
+    }
+
+    pub fn get(&self, region: &str) -> Option<&RegionEndpoints> {
+        self.regions.get(region)
+    }
+
+    /// Pick the endpoints for a server name by looking for a region key in it.
+    pub fn for_server(&self, servername: &str) -> &RegionEndpoints {
+        self.region_for_server(servername)
+            .and_then(|r| self.get(r))
+            .unwrap_or(&self.default)
+    }
+
+    /// The region whose key appears in `servername`, if any. When several do,
+    /// the longest key wins so more specific regions take precedence.
+    pub fn region_for_server(&self, servername: &str) -> Option<&str> {
+        self.regions
+            .keys()
+            .filter(|r| servername.contains(r.as_str()))
+            .max_by(|a, b| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+            .map(|r| r.as_str())
+    }
+
+    /// The region named in `servername` when it is not `region`, the region
+    /// chosen for it explicitly. Usually a sign of a copy-paste mistake.
+    pub fn conflicting_region(&self, servername: &str, region: &str) -> Option<&str> {
+        self.region_for_server(servername).filter(|r| *r != region)
+    }
+}
+
+/// Replace the compiled-in table with one read from `path`.
+///
+/// Must be called before the table is first used, i.e. before any client is
+/// constructed.
+pub fn load_override<P: AsRef<Path>>(path: P) -> Result<()> {
+    let data = std::fs::read_to_string(path.as_ref())?;
+    let table = RegionTable::parse(&data)?;
+
+    TABLE
+        .set(table)
+        .map_err(|_| "region table already in use, override must be loaded first".into())
+}
+
+/// The active region table.
+pub fn table() -> &'static RegionTable {
+    TABLE.get_or_init(|| {
+        RegionTable::parse(EMBEDDED_REGIONS).expect("embedded regions.json is invalid")
+    })
+}
+
+/// The endpoint serving `region`, or an error listing the known regions.
+pub fn endpoint_for(region: &str) -> Result<String> {
+    match table().get(region) {
+        Some(r) => Ok(r.endpoint.clone()),
+        None => Err(format!(
+            "unknown region '{}', known regions: {}",
+            region,
+            known_regions().join(", ")
+        )
+        .into()),
+    }
+}
+
+/// Names of all known regions, sorted.
+pub fn known_regions() -> Vec<String> {
+    let mut regions: Vec<String> = table().regions.keys().cloned().collect();
+    regions.sort();
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RegionTable, EMBEDDED_REGIONS};
+
+    #[test]
+    fn embedded_table_parses() {
+        let table = RegionTable::parse(EMBEDDED_REGIONS).unwrap();
+        assert!(table.get("eu-de").is_some());
+    }
+
+    #[test]
+    fn server_routing() {
+        let table = RegionTable::parse(EMBEDDED_REGIONS).unwrap();
+        assert_eq!(
+            table.for_server("be-eu-de/srv1").endpoint,
+            "https://hapctl-eu.kp-ops.net"
+        );
+        assert_eq!(
+            table.for_server("be-us-south/srv1").endpoint,
+            table.default.endpoint
+        );
+    }
+
+    #[test]
+    fn region_conflicts() {
+        let table = RegionTable::parse(EMBEDDED_REGIONS).unwrap();
+        assert_eq!(table.conflicting_region("be-eu-de/srv1", "eu-de"), None);
+        assert_eq!(table.conflicting_region("be/srv1", "eu-de"), None);
+        assert_eq!(
+            table.conflicting_region("be-eu-de/srv1", "us-south"),
+            Some("eu-de")
+        );
+    }
+}
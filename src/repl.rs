@@ -0,0 +1,75 @@
+/// Split a command line typed at the `repl` prompt into words, the way a
+/// shell would for simple cases: words are separated by whitespace, single
+/// and double quotes group words, and a backslash escapes the next character
+/// outside single quotes.
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => {
+                    word.push(escaped);
+                    in_word = true;
+                }
+                None => return Err("trailing backslash".to_string()),
+            },
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if let Some(q) = quote {
+        return Err(format!("unterminated {} quote", q));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_words;
+
+    #[test]
+    fn words_and_quotes() {
+        assert_eq!(
+            split_words(r#"set-weight web/web1 10 "drain for \"maint\"" --meta 'ticket=OPS 1'"#)
+                .unwrap(),
+            vec![
+                "set-weight",
+                "web/web1",
+                "10",
+                r#"drain for "maint""#,
+                "--meta",
+                "ticket=OPS 1"
+            ]
+        );
+        assert_eq!(
+            split_words("  get-weight   '' ").unwrap(),
+            vec!["get-weight", ""]
+        );
+        assert!(split_words("set-weight 'oops").is_err());
+        assert!(split_words("trailing \\").is_err());
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::regions::{self, RegionTable};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+static RULES: OnceLock<EndpointRules> = OnceLock::new();
+
+static BACKENDS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+static TEMPLATE: OnceLock<Option<NameTemplate>> = OnceLock::new();
+
+/// Fields a server name template may contain.
+const TEMPLATE_FIELDS: [&str; 4] = ["env", "region", "backend", "server"];
+
+/// Ordered `regex = endpoint` rules mapping server names to endpoints.
+///
+/// The file format is one rule per line; blank lines and `#` comments are
+/// ignored. The last `=` on a line separates the pattern from the endpoint:
+///
+/// ```text
+/// # all eu-gb backends live on their own host
+/// ^.*-eu-gb/ = https://hapctl-gb.example.net
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EndpointRules {
+    rules: Vec<(Regex, String)>,
+}
+
+impl EndpointRules {
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (pattern, endpoint) = line
+                .rsplit_once('=')
+                .ok_or_else(|| format!("line {}: expected 'regex = endpoint'", i + 1))?;
+            let (pattern, endpoint) = (pattern.trim(), endpoint.trim());
+            if pattern.is_empty() || endpoint.is_empty() {
+                return Err(format!("line {}: expected 'regex = endpoint'", i + 1).into());
+            }
+
+            let re = Regex::new(pattern)
+                .map_err(|e| format!("line {}: invalid regex '{}': {}", i + 1, pattern, e))?;
+            rules.push((re, endpoint.to_string()));
+        }
+
+        Ok(EndpointRules { rules })
+    }
+
+    /// The first rule matching `servername`, as `(pattern, endpoint)`.
+    pub fn matching(&self, servername: &str) -> Option<(&str, &str)> {
+        self.rules
+            .iter()
+            .find(|(re, _)| re.is_match(servername))
+            .map(|(re, endpoint)| (re.as_str(), endpoint.as_str()))
+    }
+}
+
+/// Load endpoint rules from `path`. Must be called before any client is
+/// constructed.
+pub fn load_rules<P: AsRef<Path>>(path: P) -> Result<()> {
+    let data = std::fs::read_to_string(path.as_ref())?;
+    let rules = EndpointRules::parse(&data)?;
+
+    RULES
+        .set(rules)
+        .map_err(|_| "endpoint rules already in use, they must be loaded first".into())
+}
+
+fn rules() -> &'static EndpointRules {
+    RULES.get_or_init(EndpointRules::default)
+}
+
+/// Route servers of the given backends to fixed endpoints, e.g. from the
+/// config file's `[backend_endpoints]` table. Must be called before any
+/// client is constructed.
+pub fn set_backend_endpoints(backends: BTreeMap<String, String>) -> Result<()> {
+    BACKENDS
+        .set(backends)
+        .map_err(|_| "backend endpoints already in use, they must be set first".into())
+}
+
+fn backends() -> &'static BTreeMap<String, String> {
+    BACKENDS.get_or_init(BTreeMap::new)
+}
+
+/// A pattern for structured server names such as `prod-eu-de/web1`, written
+/// as `{env}-{region}/{server}`, that picks endpoints from the environment
+/// and region embedded in the name.
+///
+/// An environment listed in `env_endpoints` uses that endpoint, with
+/// `{region}` replaced by the region from the name. Otherwise a known region
+/// uses the region table's endpoint. Names that do not match fall back to
+/// the usual heuristics.
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    template: String,
+    re: Regex,
+    env_endpoints: BTreeMap<String, String>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str, env_endpoints: BTreeMap<String, String>) -> Result<Self> {
+        let mut pattern = String::from("^");
+        let mut fields = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            pattern.push_str(&regex::escape(&rest[..start]));
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in servername template '{}'", template))?;
+            let field = &rest[start + 1..start + end];
+            if !TEMPLATE_FIELDS.contains(&field) {
+                return Err(format!(
+                    "unknown field '{{{}}}' in servername template, expected one of: {}",
+                    field,
+                    TEMPLATE_FIELDS.join(", ")
+                )
+                .into());
+            }
+            if fields.contains(&field) {
+                return Err(
+                    format!("field '{{{}}}' repeated in servername template", field).into(),
+                );
+            }
+            fields.push(field);
+            pattern.push_str(&format!("(?P<{}>.+?)", field));
+            rest = &rest[start + end + 1..];
+        }
+        pattern.push_str(&regex::escape(rest));
+        pattern.push('$');
+
+        if !fields.contains(&"env") && !fields.contains(&"region") {
+            return Err(format!(
+                "servername template '{}' must contain {{env}} or {{region}}",
+                template
+            )
+            .into());
+        }
+
+        Ok(NameTemplate {
+            template: template.to_string(),
+            re: Regex::new(&pattern)?,
+            env_endpoints,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    /// The fields of `servername`, or `None` if it does not match.
+    pub fn fields(&self, servername: &str) -> Option<BTreeMap<String, String>> {
+        let captures = self.re.captures(servername)?;
+        Some(
+            TEMPLATE_FIELDS
+                .iter()
+                .filter_map(|f| Some((f.to_string(), captures.name(f)?.as_str().to_string())))
+                .collect(),
+        )
+    }
+
+    fn resolve(&self, servername: &str, table: &RegionTable) -> Option<Resolution> {
+        let fields = self.fields(servername)?;
+        let env = fields.get("env");
+        let region = fields.get("region");
+
+        let endpoint = match env.and_then(|e| self.env_endpoints.get(e)) {
+            Some(endpoint) if endpoint.contains("{region}") => {
+                endpoint.replace("{region}", region?)
+            }
+            Some(endpoint) => endpoint.clone(),
+            None => table.get(region?)?.endpoint.clone(),
+        };
+
+        Some(Resolution {
+            endpoint,
+            source: Source::Template {
+                env: env.cloned(),
+                region: region.cloned(),
+            },
+        })
+    }
+}
+
+/// Parse structured server names with `template`, e.g. from the config
+/// file's `servername_template`. Must be called before any client is
+/// constructed.
+pub fn set_name_template(template: NameTemplate) -> Result<()> {
+    TEMPLATE
+        .set(Some(template))
+        .map_err(|_| "servername template already in use, it must be set first".into())
+}
+
+/// The configured server name template, if any.
+pub fn name_template() -> Option<&'static NameTemplate> {
+    TEMPLATE.get_or_init(|| None).as_ref()
+}
+
+/// Where a resolved endpoint came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Rule(String),
+    Region(String),
+    Backend(String),
+    Template {
+        env: Option<String>,
+        region: Option<String>,
+    },
+    Default,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::Rule(pattern) => write!(f, "endpoint rule '{}'", pattern),
+            Source::Region(region) => write!(f, "region '{}' found in server name", region),
+            Source::Backend(backend) => write!(f, "endpoint for backend '{}'", backend),
+            Source::Template { env, region } => {
+                write!(f, "servername template")?;
+                if let Some(env) = env {
+                    write!(f, ", env '{}'", env)?;
+                }
+                if let Some(region) = region {
+                    write!(f, ", region '{}'", region)?;
+                }
+                Ok(())
+            }
+            Source::Default => write!(f, "default endpoint"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    pub endpoint: String,
+    pub source: Source,
+}
+
+/// The endpoint configured for `servername` by an endpoint rule, a backend
+/// endpoint or the servername template, without falling back to the region
+/// heuristic or the default endpoint.
+pub fn resolve_configured(servername: &str) -> Option<Resolution> {
+    let rule = rules()
+        .matching(servername)
+        .map(|(pattern, endpoint)| Resolution {
+            endpoint: endpoint.to_string(),
+            source: Source::Rule(pattern.to_string()),
+        });
+
+    rule.or_else(|| backend_endpoint(servername, backends()))
+        .or_else(|| name_template()?.resolve(servername, regions::table()))
+}
+
+/// The endpoint configured for `servername`'s backend, if any. It takes
+/// precedence over any region, including one chosen with `--region`.
+pub fn resolve_backend(servername: &str) -> Option<Resolution> {
+    backend_endpoint(servername, backends())
+}
+
+/// Pick the endpoint for `servername`, in order: the first matching endpoint
+/// rule, the endpoint configured for its backend, the servername template, a
+/// region named in the server name, then the default endpoint.
+pub fn resolve(servername: &str) -> Resolution {
+    resolve_in(
+        servername,
+        rules(),
+        name_template(),
+        regions::table(),
+        backends(),
+    )
+}
+
+fn resolve_in(
+    servername: &str,
+    rules: &EndpointRules,
+    template: Option<&NameTemplate>,
+    table: &RegionTable,
+    backends: &BTreeMap<String, String>,
+) -> Resolution {
+    if let Some((pattern, endpoint)) = rules.matching(servername) {
+        return Resolution {
+            endpoint: endpoint.to_string(),
+            source: Source::Rule(pattern.to_string()),
+        };
+    }
+
+    if let Some(resolution) = backend_endpoint(servername, backends) {
+        return resolution;
+    }
+
+    if let Some(resolution) = template.and_then(|t| t.resolve(servername, table)) {
+        return resolution;
+    }
+
+    if let Some(region) = table.region_for_server(servername) {
+        return Resolution {
+            endpoint: table.for_server(servername).endpoint.clone(),
+            source: Source::Region(region.to_string()),
+        };
+    }
+
+    Resolution {
+        endpoint: table.default.endpoint.clone(),
+        source: Source::Default,
+    }
+}
+
+fn backend_endpoint(servername: &str, backends: &BTreeMap<String, String>) -> Option<Resolution> {
+    let backend = servername
+        .split(crate::client::servername_delimiter())
+        .next()?;
+    backends.get(backend).map(|endpoint| Resolution {
+        endpoint: endpoint.clone(),
+        source: Source::Backend(backend.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backend_endpoint, resolve_in, EndpointRules, NameTemplate, Source};
+
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::regions::{RegionEndpoints, RegionTable};
+
+    #[test]
+    fn resolution_order() {
+        let endpoint = |e: &str| RegionEndpoints {
+            endpoint: e.to_string(),
+        };
+        let table = RegionTable {
+            default: endpoint("https://default"),
+            regions: HashMap::from([("eu-de".to_string(), endpoint("https://eu"))]),
+        };
+        let rules = EndpointRules::parse("^canary/ = https://canary").unwrap();
+        let backends = BTreeMap::from([
+            ("canary".to_string(), "https://canary-backend".to_string()),
+            ("web-eu-de".to_string(), "https://web-backend".to_string()),
+            ("api".to_string(), "https://api-backend".to_string()),
+        ]);
+
+        let source = |name| resolve_in(name, &rules, None, &table, &backends).source;
+        assert_eq!(source("canary/s1"), Source::Rule("^canary/".to_string()));
+        assert_eq!(
+            source("web-eu-de/s1"),
+            Source::Backend("web-eu-de".to_string())
+        );
+        assert_eq!(source("app-eu-de/s1"), Source::Region("eu-de".to_string()));
+        assert_eq!(source("api/s1"), Source::Backend("api".to_string()));
+        assert_eq!(source("other/s1"), Source::Default);
+        assert_eq!(
+            resolve_in("api/s1", &rules, None, &table, &backends).endpoint,
+            "https://api-backend"
+        );
+    }
+
+    #[test]
+    fn backend_wins_over_region() {
+        let table = RegionTable {
+            default: RegionEndpoints {
+                endpoint: "https://default".to_string(),
+            },
+            regions: HashMap::from([(
+                "eu-de".to_string(),
+                RegionEndpoints {
+                    endpoint: "https://eu".to_string(),
+                },
+            )]),
+        };
+        let template = NameTemplate::parse("{region}-{backend}/{server}", BTreeMap::new()).unwrap();
+        let backends = BTreeMap::from([(
+            "eu-de-backend-x".to_string(),
+            "https://hapctl-x".to_string(),
+        )]);
+
+        let resolution = resolve_in(
+            "eu-de-backend-x/web1",
+            &EndpointRules::default(),
+            Some(&template),
+            &table,
+            &backends,
+        );
+        assert_eq!(resolution.endpoint, "https://hapctl-x");
+        assert_eq!(
+            backend_endpoint("eu-de-backend-x/web1", &backends)
+                .unwrap()
+                .endpoint,
+            "https://hapctl-x"
+        );
+        assert!(backend_endpoint("eu-de-web/web1", &backends).is_none());
+    }
+
+    #[test]
+    fn servername_template() {
+        let table = RegionTable {
+            default: RegionEndpoints {
+                endpoint: "https://default".to_string(),
+            },
+            regions: HashMap::from([(
+                "eu-de".to_string(),
+                RegionEndpoints {
+                    endpoint: "https://eu".to_string(),
+                },
+            )]),
+        };
+        let template = NameTemplate::parse(
+            "{env}-{region}/{server}",
+            BTreeMap::from([(
+                "staging".to_string(),
+                "https://staging.{region}.example".to_string(),
+            )]),
+        )
+        .unwrap();
+
+        let fields = template.fields("prod-eu-de/web1").unwrap();
+        assert_eq!(fields["env"], "prod");
+        assert_eq!(fields["region"], "eu-de");
+        assert_eq!(fields["server"], "web1");
+        assert!(template.fields("web1").is_none());
+
+        let rules = EndpointRules::default();
+        let backends = BTreeMap::new();
+        let resolve = |name| resolve_in(name, &rules, Some(&template), &table, &backends);
+        assert_eq!(resolve("prod-eu-de/web1").endpoint, "https://eu");
+        assert_eq!(
+            resolve("staging-us-south/web1").endpoint,
+            "https://staging.us-south.example"
+        );
+        assert_eq!(
+            resolve("staging-eu-de/web1").source,
+            Source::Template {
+                env: Some("staging".to_string()),
+                region: Some("eu-de".to_string())
+            }
+        );
+        // Matches, but names neither a configured env nor a known region.
+        assert_eq!(resolve("prod-mars/web1").source, Source::Default);
+
+        assert!(NameTemplate::parse("{backend}/{server}", BTreeMap::new()).is_err());
+        let err = NameTemplate::parse("{env}-{zone}/{server}", BTreeMap::new()).unwrap_err();
+        assert!(err.to_string().starts_with("unknown field '{zone}'"));
+        assert!(NameTemplate::parse("{env/{server}", BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn rules_match_in_order() {
+        let rules = EndpointRules::parse(
+            "# comment\n\n^web-.*/ = https://web.example\n.*/canary = https://canary.example\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            rules.matching("web-eu/canary"),
+            Some(("^web-.*/", "https://web.example"))
+        );
+        assert_eq!(
+            rules.matching("api/canary"),
+            Some((".*/canary", "https://canary.example"))
+        );
+        assert_eq!(rules.matching("api/srv1"), None);
+    }
+
+    #[test]
+    fn invalid_regex_names_line() {
+        let err = EndpointRules::parse("ok = https://a\n([ = https://b\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 2: invalid regex '(['"));
+    }
+
+    #[test]
+    fn missing_endpoint() {
+        assert!(EndpointRules::parse("^web-.*\n").is_err());
+    }
+}
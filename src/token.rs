@@ -0,0 +1,264 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use tracing::{debug, info};
+
+use crate::iam::{self, expires_in_secs, Token};
+use crate::reason::utc_timestamp;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// How long a token read from a file is trusted when it carries no `exp`
+/// claim, matching the IAM default token lifetime.
+const DEFAULT_FILE_TOKEN_LIFETIME: Duration = Duration::from_secs(1200);
+
+/// Re-read a token file once its token is this close to expiring.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// Source of the bearer tokens `hapctl::Client` sends.
+pub trait TokenProvider: Send + Sync {
+    /// A currently valid token, fetching a new one if needed.
+    fn token(&self) -> Result<Token>;
+
+    /// Drop any cached token so the next `token()` call fetches a new one.
+    fn invalidate(&self) {}
+}
+
+impl TokenProvider for iam::Client {
+    fn token(&self) -> Result<Token> {
+        Ok(iam::Client::token(self)?)
+    }
+
+    fn invalidate(&self) {
+        iam::Client::invalidate(self)
+    }
+}
+
+/// Reads the access token from a file kept up to date by something else,
+/// such as a sidecar that refreshes it.
+///
+/// The file is re-read whenever its modification time changes or the cached
+/// token is about to expire. Expiry comes from the JWT `exp` claim when the
+/// token has one.
+pub struct FileTokenProvider {
+    path: PathBuf,
+    cached: Mutex<Option<(SystemTime, Token)>>,
+}
+
+impl FileTokenProvider {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileTokenProvider {
+            path: path.into(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn read(&self) -> Result<Token> {
+        let data = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("reading token file {}: {}", self.path.display(), e))?;
+        let access_token = data.trim().to_string();
+        if access_token.is_empty() {
+            return Err(format!("token file {} is empty", self.path.display()).into());
+        }
+
+        let expiry = jwt_expiry(&access_token)
+            .unwrap_or_else(|| Instant::now() + DEFAULT_FILE_TOKEN_LIFETIME);
+
+        Ok(Token::new(&access_token, "Bearer", "", expiry))
+    }
+}
+
+impl TokenProvider for FileTokenProvider {
+    fn token(&self) -> Result<Token> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .map_err(|e| format!("reading token file {}: {}", self.path.display(), e))?;
+
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((mtime, token)) = cached.as_ref() {
+            let fresh = token.time_to_expiry().is_some_and(|d| d > REFRESH_MARGIN);
+            if *mtime == modified && fresh {
+                debug!(
+                    source = "cache",
+                    expires_in_secs = expires_in_secs(token),
+                    "token from cache"
+                );
+                return Ok(token.clone());
+            }
+        }
+
+        let token = self.read()?;
+        if !token.valid() {
+            return Err(format!("token in {} has expired", self.path.display()).into());
+        }
+        info!(
+            source = "file",
+            path = %self.path.display(),
+            expires_in_secs = expires_in_secs(&token),
+            "token loaded from file"
+        );
+
+        *cached = Some((modified, token.clone()));
+        Ok(token)
+    }
+
+    fn invalidate(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+}
+
+/// A token provider for tests that caches its token like `iam::Client` and
+/// counts how often it had to fetch a new one.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct CountingProvider {
+    pub fetches: std::sync::atomic::AtomicUsize,
+    cached: Mutex<Option<Token>>,
+}
+
+#[cfg(test)]
+impl TokenProvider for CountingProvider {
+    fn token(&self) -> Result<Token> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some(token) = cached.as_ref().filter(|t| t.valid()) {
+            return Ok(token.clone());
+        }
+
+        self.fetches
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let token = Token::new(
+            "test-token",
+            "Bearer",
+            "",
+            Instant::now() + Duration::from_secs(600),
+        );
+        *cached = Some(token.clone());
+        Ok(token)
+    }
+
+    fn invalidate(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+}
+
+/// The decoded claims of a JWT, or `None` if `token` is not a JWT. The
+/// signature is not checked.
+pub fn jwt_claims(token: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let payload = token.split('.').nth(1)?;
+    let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    serde_json::from_slice(&decoded).ok()
+}
+
+/// The expiry of a JWT from its `exp` claim, or `None` if `token` is not a
+/// JWT or has no `exp`. The signature is not checked.
+pub fn jwt_expiry(token: &str) -> Option<Instant> {
+    let claims = jwt_claims(token)?;
+    let exp = UNIX_EPOCH + Duration::from_secs(claims.get("exp")?.as_u64()?);
+
+    let now = Instant::now();
+    Some(match exp.duration_since(SystemTime::now()) {
+        Ok(remaining) => now + remaining,
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now),
+    })
+}
+
+/// Token metadata as JSON for scripts: type, expiry (RFC 3339 and seconds
+/// left) and the JWT claims. The access and refresh tokens are only included
+/// with `show_token`.
+pub fn details(token: &Token, show_token: bool) -> serde_json::Value {
+    let remaining = token.time_to_expiry().unwrap_or_default();
+    let mut details = serde_json::json!({
+        "token_type": token.token_type,
+        "expires_at": utc_timestamp(SystemTime::now() + remaining),
+        "expires_in_secs": remaining.as_secs(),
+        "claims": jwt_claims(&token.access_token),
+    });
+    if show_token {
+        details["access_token"] = token.access_token.clone().into();
+        details["refresh_token"] = token.refresh_token.clone().into();
+    }
+    details
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{details, jwt_expiry, FileTokenProvider, TokenProvider};
+
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use base64::Engine;
+
+    use crate::iam::Token;
+
+    fn jwt(claims: &str) -> String {
+        let enc = |s: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(s);
+        format!("{}.{}.sig", enc(r#"{"alg":"none"}"#), enc(claims))
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn expiry_from_jwt() {
+        let token = jwt(&format!(r#"{{"exp":{}}}"#, unix_now() + 600));
+        let remaining = jwt_expiry(&token)
+            .unwrap()
+            .saturating_duration_since(std::time::Instant::now());
+        assert!(remaining > Duration::from_secs(590));
+        assert!(remaining <= Duration::from_secs(600));
+
+        assert!(jwt_expiry(&jwt(r#"{"sub":"x"}"#)).is_none());
+        assert!(jwt_expiry("opaque-token").is_none());
+    }
+
+    #[test]
+    fn token_details() {
+        let token = Token {
+            access_token: jwt(r#"{"sub":"iam-ServiceId-1","exp":4102444800}"#),
+            token_type: "Bearer".to_string(),
+            refresh_token: "not_supported".to_string(),
+            expiry: Instant::now() + Duration::from_secs(600),
+        };
+
+        let redacted = details(&token, false);
+        assert_eq!(redacted["token_type"], "Bearer");
+        assert_eq!(redacted["claims"]["sub"], "iam-ServiceId-1");
+        assert!(redacted["expires_in_secs"].as_u64().unwrap() > 590);
+        assert!(redacted["expires_at"].as_str().unwrap().ends_with('Z'));
+        assert!(redacted.get("access_token").is_none());
+
+        let shown = details(&token, true);
+        assert_eq!(shown["access_token"], token.access_token.as_str());
+    }
+
+    #[test]
+    fn file_reread_on_change() {
+        let path = std::env::temp_dir().join(format!("hapctl-token-{}", std::process::id()));
+        std::fs::write(&path, "first\n").unwrap();
+
+        let provider = FileTokenProvider::new(&path);
+        assert_eq!(provider.token().unwrap().access_token, "first");
+
+        std::fs::write(&path, "second\n").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(provider.token().unwrap().access_token, "second");
+
+        let expired = jwt(&format!(r#"{{"exp":{}}}"#, unix_now() - 60));
+        std::fs::write(&path, &expired).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(provider.token().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}